@@ -0,0 +1,36 @@
+#!/bin/sh
+# Compares throughput of whole-frame conversion (--full-frame, how frames were
+# converted before cropping at the decoder) with the cropped decode, on the
+# same stretch of the same file.
+#
+#   scripts/bench-decode.sh <video> [seconds] [runs]
+#
+# Every frame is analyzed (--sample-fps 0) so each one is converted. Prints
+# the "decoded N frames in Ts  F fps" line of every run.
+set -eu
+
+if [ $# -lt 1 ]; then
+    echo "usage: $0 <video> [seconds] [runs]" >&2
+    exit 1
+fi
+
+video=$1
+seconds=${2:-60}
+runs=${3:-3}
+
+cargo build --release --quiet
+
+for mode in full-frame cropped; do
+    flag=""
+    if [ "$mode" = full-frame ]; then
+        flag="--full-frame"
+    fi
+
+    i=1
+    while [ "$i" -le "$runs" ]; do
+        RUST_LOG=info ./target/release/stream-cv --url "$video" --end "$seconds" \
+            --sample-fps 0 $flag 2>&1 |
+            sed -n "s/.*\(decoded [0-9]* frames in .*\)/$mode\trun $i\t\1/p"
+        i=$((i + 1))
+    done
+done
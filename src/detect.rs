@@ -0,0 +1,305 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use crate::{
+    error::Result,
+    games::{Game, SupportedGames},
+    img::{
+        frame::Frame,
+        model,
+        template::{self, Template},
+    },
+    reading::Reading,
+    roi::{self, RegionOfInterest, RegionOfInterestList},
+    Config,
+};
+
+/// Share of a game's signature regions that have to be seen before its HUD
+/// counts as on screen.
+const MIN_SIGNATURE_SHARE: f64 = 0.5;
+
+/// Confidence a signature region without a template has to be read with.
+/// Enum regions always read some word and numbers get corrected, so any
+/// screen parses now and then; it's the confidence that tells a HUD apart.
+const MIN_SIGNATURE_CONFIDENCE: f64 = 0.8;
+
+/// Probes in a row that have to agree before the game on screen changes.
+const CONFIRMATIONS: u32 = 2;
+
+/// What a frame's regions are read for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    /// The HUD of the game on screen.
+    Hud(SupportedGames),
+    /// Every game's signature regions, to tell which game is on screen.
+    Probe,
+}
+
+/// The game on screen changing, `None` meaning no known HUD.
+#[derive(Debug, Clone, Copy)]
+pub struct Switch {
+    pub from: Option<SupportedGames>,
+    pub to: Option<SupportedGames>,
+}
+
+struct Hud {
+    id: SupportedGames,
+    game: Arc<dyn Game>,
+    regions: RegionOfInterestList,
+    /// Templates of signature regions that have one, by region name.
+    templates: HashMap<String, Template>,
+}
+
+#[derive(Debug)]
+struct Detection {
+    active: Option<SupportedGames>,
+    /// What the latest probes saw, and how many in a row saw it.
+    candidate: Option<SupportedGames>,
+    seen: u32,
+}
+
+/// The games whose HUDs can be read, and which one is on screen.
+///
+/// A game's signature is the regions with a `signature = "true"` option:
+/// fixed HUD elements that only show while its HUD is on screen. A region
+/// with a `template` option, an image of the element relative to the model
+/// directory, is seen when the template matches its crop with at least its
+/// `template_threshold` score. Other signature regions are seen when read
+/// confidently. With detection on, a probe frame covering every game's
+/// signature is taken every `interval` seconds, and the game whose
+/// signature is seen best becomes the one whose HUD is read. Frames aren't
+/// read at all while none is, including at the start.
+pub struct GameDetector {
+    huds: Vec<Hud>,
+    detecting: bool,
+    interval: f64,
+    detection: Mutex<Detection>,
+}
+
+/// Reads `config.game`, or if `config.detect_game` is set detects the game
+/// on screen among `games`, loading their signature templates.
+pub fn new(
+    games: Vec<(SupportedGames, Arc<dyn Game>, RegionOfInterestList)>,
+    config: &Config,
+) -> Result<GameDetector> {
+    let mut huds = Vec::new();
+    for (id, game, regions) in games {
+        let mut templates = HashMap::new();
+        if config.detect_game {
+            for (name, region) in signature(&regions).iter() {
+                if let Some(template) = load_template(&region, config)? {
+                    templates.insert(name, template);
+                }
+            }
+        }
+
+        huds.push(Hud {
+            id,
+            game,
+            regions,
+            templates,
+        });
+    }
+
+    if config.detect_game {
+        for hud in &huds {
+            if signature(&hud.regions).is_empty() {
+                warn!(
+                    "{} has no signature regions, it won't be detected",
+                    hud.game.name()
+                );
+            }
+        }
+        info!(
+            "detecting the game on screen every {}s",
+            config.detect_interval
+        );
+    }
+
+    // while detecting nothing is read until a game has been seen
+    let active = if config.detect_game {
+        None
+    } else {
+        Some(config.game)
+    };
+
+    Ok(GameDetector {
+        huds,
+        detecting: config.detect_game,
+        interval: config.detect_interval,
+        detection: Mutex::new(Detection {
+            active,
+            candidate: active,
+            seen: 0,
+        }),
+    })
+}
+
+fn load_template(region: &RegionOfInterest, config: &Config) -> Result<Option<Template>> {
+    let path = match region.options.get("template") {
+        Some(path) => model::model_dir(config).join(path),
+        None => return Ok(None),
+    };
+    let threshold = region
+        .options
+        .get("template_threshold")
+        .and_then(|threshold| threshold.parse().ok())
+        .unwrap_or(template::DEFAULT_THRESHOLD);
+
+    template::load(&path, threshold).map(Some)
+}
+
+fn signature(regions: &RegionOfInterestList) -> RegionOfInterestList {
+    let mut signature = roi::new_region_list();
+    for (_, region) in regions.iter() {
+        if region.options.get("signature").map(String::as_str) == Some("true") {
+            signature.add_region(region);
+        }
+    }
+
+    signature
+}
+
+/// Region name that's unique across games.
+fn qualified(game: SupportedGames, name: &str) -> String {
+    format!("{}/{}", game, name)
+}
+
+impl GameDetector {
+    pub fn is_detecting(&self) -> bool {
+        self.detecting
+    }
+
+    /// Seconds between probes.
+    pub fn interval(&self) -> f64 {
+        self.interval
+    }
+
+    /// The game whose HUD is on screen, `None` while none is.
+    pub fn active(&self) -> Option<SupportedGames> {
+        self.detection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .active
+    }
+
+    pub fn game(&self, id: SupportedGames) -> Option<&Arc<dyn Game>> {
+        self.huds
+            .iter()
+            .find(|hud| hud.id == id)
+            .map(|hud| &hud.game)
+    }
+
+    /// Each game's regions.
+    pub fn hud_regions(&self) -> HashMap<SupportedGames, RegionOfInterestList> {
+        self.huds
+            .iter()
+            .map(|hud| (hud.id, hud.regions.clone()))
+            .collect()
+    }
+
+    /// Every game's signature regions, under qualified names.
+    pub fn probe_regions(&self) -> RegionOfInterestList {
+        let mut probe = roi::new_region_list();
+        for hud in &self.huds {
+            for (name, mut region) in signature(&hud.regions).iter() {
+                region.name = qualified(hud.id, &name);
+                probe.add_region(region);
+            }
+        }
+
+        probe
+    }
+
+    /// Every game's regions in one list, under qualified names.
+    pub fn all_regions(&self) -> RegionOfInterestList {
+        let mut all = roi::new_region_list();
+        for hud in &self.huds {
+            for (name, mut region) in hud.regions.iter() {
+                region.name = qualified(hud.id, &name);
+                all.add_region(region);
+            }
+        }
+
+        all
+    }
+
+    /// Whether the signature region `name` of `hud` is on screen in `probe`.
+    fn sees(&self, hud: &Hud, name: &str, probe: &Frame) -> bool {
+        let region = match probe.results.get_value(qualified(hud.id, name)) {
+            Some(region) => region,
+            None => return false,
+        };
+
+        match hud.templates.get(name) {
+            Some(template) => {
+                // templates are taken at the region's base resolution
+                let scale = region.height as f64
+                    / (region.bounds.height * region.base_resolution.height as f64);
+                let matched = probe
+                    .extract_roi(region.clone())
+                    .and_then(|crop| template.matches(&crop, scale));
+
+                matched.unwrap_or_else(|err| {
+                    warn!(
+                        "frame {}\tunable to match the template of {}\t{}",
+                        probe.num, region.name, err
+                    );
+                    false
+                })
+            }
+            None => match &region.reading {
+                Some(Reading::Parsed { confidence, .. }) => *confidence >= MIN_SIGNATURE_CONFIDENCE,
+                _ => false,
+            },
+        }
+    }
+
+    /// Share of `hud`'s signature seen in a probe.
+    fn signature_share(&self, hud: &Hud, probe: &Frame) -> f64 {
+        let signature = signature(&hud.regions);
+        if signature.is_empty() {
+            return 0.;
+        }
+
+        let seen = signature
+            .iter()
+            .filter(|(name, _)| self.sees(hud, name, probe))
+            .count();
+
+        seen as f64 / signature.len() as f64
+    }
+
+    /// Works out which game's HUD a probe frame shows, returning the switch
+    /// if that changes the game on screen.
+    pub fn classify(&self, probe: &Frame) -> Option<Switch> {
+        let seen = self
+            .huds
+            .iter()
+            .map(|hud| (hud.id, self.signature_share(hud, probe)))
+            .filter(|(_, share)| *share >= MIN_SIGNATURE_SHARE)
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(id, _)| id);
+
+        let mut detection = self
+            .detection
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if detection.candidate == seen {
+            detection.seen += 1;
+        } else {
+            detection.candidate = seen;
+            detection.seen = 1;
+        }
+
+        if detection.seen >= CONFIRMATIONS && detection.active != seen {
+            let from = detection.active;
+            detection.active = seen;
+            Some(Switch { from, to: seen })
+        } else {
+            None
+        }
+    }
+}
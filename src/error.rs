@@ -0,0 +1,84 @@
+use clap::ArgEnum;
+use std::{fmt, io, path::PathBuf};
+
+use crate::layout::LayoutError;
+
+pub type Result<T> = std::result::Result<T, StreamCvError>;
+
+#[derive(Debug)]
+pub enum StreamCvError {
+    Decode(ffmpeg_next::Error),
+    Scale(ffmpeg_next::Error),
+    OpenCv(opencv::Error),
+    ModelLoad {
+        path: PathBuf,
+        reason: String,
+    },
+    Ocr(String),
+    RegionOutOfBounds {
+        name: String,
+        rect: (i32, i32, i32, i32),
+        frame: (i32, i32),
+    },
+    Sink(io::Error),
+    Layout(LayoutError),
+    Config(String),
+    /// The pipeline stopped taking frames.
+    PipelineClosed,
+    /// A frame failed and the error policy is to abort.
+    Aborted {
+        frame: i64,
+        source: Box<StreamCvError>,
+    },
+}
+
+impl fmt::Display for StreamCvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamCvError::Decode(err) => write!(f, "decoding failed: {}", err),
+            StreamCvError::Scale(err) => write!(f, "converting a frame failed: {}", err),
+            StreamCvError::OpenCv(err) => write!(f, "opencv error: {}", err),
+            StreamCvError::ModelLoad { path, reason } => {
+                write!(f, "unable to load model {}: {}", path.display(), reason)
+            }
+            StreamCvError::Ocr(reason) => write!(f, "text recognition failed: {}", reason),
+            StreamCvError::RegionOutOfBounds { name, rect, frame } => write!(
+                f,
+                "region {} at {}x{}+{}+{} is outside of the {}x{} frame",
+                name, rect.2, rect.3, rect.0, rect.1, frame.0, frame.1
+            ),
+            StreamCvError::Sink(err) => write!(f, "unable to write results: {}", err),
+            StreamCvError::Layout(err) => write!(f, "{}", err),
+            StreamCvError::Config(reason) => write!(f, "invalid configuration: {}", reason),
+            StreamCvError::PipelineClosed => write!(f, "the pipeline stopped accepting frames"),
+            StreamCvError::Aborted { frame, source } => {
+                write!(f, "aborting after frame {} failed: {}", frame, source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StreamCvError {}
+
+impl From<opencv::Error> for StreamCvError {
+    fn from(err: opencv::Error) -> Self {
+        StreamCvError::OpenCv(err)
+    }
+}
+
+impl From<LayoutError> for StreamCvError {
+    fn from(err: LayoutError) -> Self {
+        StreamCvError::Layout(err)
+    }
+}
+
+/// What the pipeline does with a frame that fails to process.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameErrorPolicy {
+    /// Log the error and move on without the frame.
+    Skip,
+    /// Process the frame again, skipping it if it keeps failing.
+    Retry,
+    /// Stop processing the stream.
+    Abort,
+}
@@ -1,74 +1,100 @@
+use std::fmt;
+
 use enum_iterator::Sequence;
+use serde::Serialize;
 
 use crate::{
-    games::Game,
-    roi::{self, StreamResolution},
+    games::{self, show, Game, GameData},
+    img::frame::Frame,
+    layout::{self, RegionLayout},
+    reading::Reading,
+    state::{self, MatchPhase},
 };
 
-pub struct Apex;
+const LAYOUT: &str = include_str!("../../layouts/apex.toml");
+
+pub struct Apex {
+    layout: RegionLayout,
+}
 
 pub fn new() -> Apex {
-    Apex
+    let mut layout = layout::from_toml(LAYOUT).expect("built-in apex layout is invalid");
+
+    let weapons = enum_iterator::all::<Weapon>()
+        .map(|weapon| weapon.to_string())
+        .collect();
+    layout
+        .vocabularies
+        .insert("apex_weapons".to_string(), weapons);
+
+    Apex { layout }
 }
 
 impl Game for Apex {
-    fn regions(&self) -> crate::roi::RegionOfInterestList {
-        let mut list = roi::new_region_list();
-
-        list.add_new_region(
-            "loaded_mag_size".to_string(),
-            1720,
-            960,
-            62,
-            40,
-            StreamResolution::HD1080p,
-        );
-        list.add_new_region(
-            "total_ammo".to_string(),
-            1720,
-            998,
-            62,
-            30,
-            StreamResolution::HD1080p,
-        );
-        list.add_new_region(
-            "weapon_1_name".to_string(),
-            1555,
-            1034,
-            110,
-            24,
-            StreamResolution::HD1080p,
-        );
-        list.add_new_region(
-            "weapon_2_name".to_string(),
-            1715,
-            1034,
-            110,
-            24,
-            StreamResolution::HD1080p,
-        );
-        list.add_new_region(
-            "compass_number".to_string(),
-            935,
-            90,
-            50,
-            32,
-            StreamResolution::HD1080p,
-        );
-
-        list
+    fn name(&self) -> &'static str {
+        "Apex Legends"
+    }
+
+    fn layout(&self) -> &RegionLayout {
+        &self.layout
+    }
+
+    fn match_phase(&self, frame: &Frame) -> Option<MatchPhase> {
+        state::classify_phase(&frame.results)
     }
 
-    fn extract_data(&self, _frame: crate::img::frame::Frame) {
-        todo!()
+    fn extract_data(&self, frame: &Frame) -> GameData {
+        let read = |name: &str| {
+            frame
+                .results
+                .get_value(name.to_string())
+                .and_then(|region| region.reading.as_ref())
+        };
+        let number = |name: &str| read(name).and_then(Reading::integer);
+        let weapon = |name: &str| {
+            read(name)
+                .and_then(Reading::text)
+                .and_then(|text| Weapon::match_string(text.to_string()))
+                .map(|(weapon, _)| weapon)
+        };
+
+        GameData::Apex(ApexHud {
+            weapon_1: weapon("weapon_1_name"),
+            weapon_2: weapon("weapon_2_name"),
+            loaded_mag: number("loaded_mag_size").and_then(|n| n.try_into().ok()),
+            total_ammo: number("total_ammo").and_then(|n| n.try_into().ok()),
+            heading: number("compass_number").and_then(|n| n.try_into().ok()),
+        })
     }
 
-    fn output(&self) -> &str {
-        todo!()
+    fn output(&self, data: &GameData) -> String {
+        match data {
+            GameData::Apex(hud) => format!(
+                "weapon 1: {}\tweapon 2: {}\tammo: {}/{}\theading: {}",
+                show(&hud.weapon_1),
+                show(&hud.weapon_2),
+                show(&hud.loaded_mag),
+                show(&hud.total_ammo),
+                show(&hud.heading),
+            ),
+            _ => String::new(),
+        }
     }
 }
 
-#[derive(Debug, Copy, Clone, Ord, PartialOrd, PartialEq, Eq, Sequence)]
+/// What the Apex HUD showed in a single frame. Values that could not be read
+/// are `None`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ApexHud {
+    pub weapon_1: Option<Weapon>,
+    pub weapon_2: Option<Weapon>,
+    pub loaded_mag: Option<u32>,
+    pub total_ammo: Option<u32>,
+    /// Compass heading in degrees.
+    pub heading: Option<u16>,
+}
+
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, PartialEq, Eq, Sequence, Serialize)]
 pub enum Weapon {
     Spitfire,
     Mozambique,
@@ -98,35 +124,14 @@ pub enum Weapon {
 }
 
 impl Weapon {
-    pub fn match_string(mut s: String) -> Option<(Weapon, f64)> {
-        let weapons = enum_iterator::all::<Weapon>().collect::<Vec<_>>();
-        s.make_ascii_lowercase();
-
-        if let Some(max) = weapons
-            .iter()
-            .cloned()
-            .map(|w| {
-                (
-                    w,
-                    strsim::jaro_winkler(&s.to_string(), &w.to_string().to_lowercase()),
-                )
-            })
-            .max_by(|w1, w2| w1.1.total_cmp(&w2.1))
-        {
-            if max.1 > 0.75 {
-                Some(max)
-            } else {
-                None
-            }
-        } else {
-            None
-        }
+    pub fn match_string(s: String) -> Option<(Weapon, f64)> {
+        games::closest_variant(&s)
     }
 }
 
-impl ToString for Weapon {
-    fn to_string(&self) -> String {
-        let s = match self {
+impl fmt::Display for Weapon {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
             Weapon::Spitfire => "Spitfire",
             Weapon::Mozambique => "Mozambique",
             Weapon::RE45 => "RE-45",
@@ -154,6 +159,6 @@ impl ToString for Weapon {
             Weapon::Havoc => "Havoc",
         };
 
-        s.to_string()
+        write!(f, "{}", name)
     }
 }
@@ -1,20 +1,103 @@
-use crate::{img::frame::Frame, roi::RegionOfInterestList};
+use clap::ArgEnum;
+use enum_iterator::Sequence;
+use serde::Serialize;
+use std::fmt;
+
+use crate::{
+    img::{frame::Frame, preprocess::Graphs},
+    layout::RegionLayout,
+    reading::Vocabularies,
+    roi::RegionOfInterestList,
+    state::MatchPhase,
+};
 
 pub mod apex;
+pub mod valorant;
 
-#[derive(Debug)]
+/// Games that can be picked with `--game`. Adding one takes a variant here,
+/// an arm in `new` and a `GameData` variant for what it reads.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq, Hash, Sequence, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum SupportedGames {
     Apex,
+    Valorant,
+}
+
+impl fmt::Display for SupportedGames {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SupportedGames::Apex => "apex",
+            SupportedGames::Valorant => "valorant",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+pub trait Game: Send + Sync {
+    /// Name the game goes by in logs.
+    fn name(&self) -> &'static str;
+    /// The game's regions, with the processing graphs they can pick and the
+    /// vocabularies their enum regions read from.
+    fn layout(&self) -> &RegionLayout;
+    fn regions(&self) -> RegionOfInterestList {
+        self.layout().regions()
+    }
+    fn graphs(&self) -> Graphs {
+        self.layout().graphs.clone()
+    }
+    /// Words the game's enum regions are matched against, by vocabulary name.
+    fn vocabularies(&self) -> Vocabularies {
+        self.layout().vocabularies.clone()
+    }
+    /// The match phase `frame` shows, for games that track one.
+    fn match_phase(&self, _frame: &Frame) -> Option<MatchPhase> {
+        None
+    }
+    fn extract_data(&self, frame: &Frame) -> GameData;
+    fn output(&self, data: &GameData) -> String;
 }
 
-pub trait Game {
-    fn regions(&self) -> RegionOfInterestList;
-    fn extract_data(&self, frame: Frame);
-    fn output(&self) -> &str;
+/// The typed values a game read from a single processed frame.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "game", rename_all = "snake_case")]
+pub enum GameData {
+    Apex(apex::ApexHud),
+    Valorant(valorant::ValorantHud),
 }
 
-pub fn new(game: SupportedGames) -> impl Game {
+pub fn new(game: SupportedGames) -> Box<dyn Game> {
     match game {
-        SupportedGames::Apex => apex::new(),
+        SupportedGames::Apex => Box::new(apex::new()),
+        SupportedGames::Valorant => Box::new(valorant::new()),
     }
 }
+
+/// A value for output lines, `-` if it couldn't be read.
+fn show<T: ToString>(value: &Option<T>) -> String {
+    value
+        .as_ref()
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// The variant of `T` whose name is closest to `text`, ignoring case, if
+/// it's similar enough to be taken for it.
+pub fn closest_variant<T: Sequence + ToString>(text: &str) -> Option<(T, f64)> {
+    let text = text.to_lowercase();
+
+    enum_iterator::all::<T>()
+        .map(|variant| {
+            let similarity = strsim::jaro_winkler(&text, &variant.to_string().to_lowercase());
+            (variant, similarity)
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .filter(|(_, similarity)| *similarity > 0.75)
+}
+
+/// Every supported game, in the order they're declared.
+pub fn registry() -> Vec<(SupportedGames, Box<dyn Game>)> {
+    enum_iterator::all::<SupportedGames>()
+        .map(|game| (game, new(game)))
+        .collect()
+}
@@ -0,0 +1,218 @@
+use std::fmt;
+
+use enum_iterator::Sequence;
+use serde::Serialize;
+
+use crate::{
+    games::{self, show, Game, GameData},
+    img::frame::Frame,
+    layout::{self, RegionLayout},
+    reading::Reading,
+};
+
+const LAYOUT: &str = include_str!("../../layouts/valorant.toml");
+
+pub struct Valorant {
+    layout: RegionLayout,
+}
+
+pub fn new() -> Valorant {
+    let mut layout = layout::from_toml(LAYOUT).expect("built-in valorant layout is invalid");
+
+    let weapons = enum_iterator::all::<Weapon>()
+        .map(|weapon| weapon.to_string())
+        .collect();
+    layout
+        .vocabularies
+        .insert("valorant_weapons".to_string(), weapons);
+
+    Valorant { layout }
+}
+
+impl Game for Valorant {
+    fn name(&self) -> &'static str {
+        "Valorant"
+    }
+
+    fn layout(&self) -> &RegionLayout {
+        &self.layout
+    }
+
+    fn extract_data(&self, frame: &Frame) -> GameData {
+        let read = |name: &str| {
+            frame
+                .results
+                .get_value(name.to_string())
+                .and_then(|region| region.reading.as_ref())
+        };
+        let number = |name: &str| read(name).and_then(Reading::integer);
+
+        GameData::Valorant(ValorantHud {
+            round_time: read("round_timer")
+                .and_then(Reading::text)
+                .and_then(parse_round_time),
+            ally_score: number("ally_score").and_then(|n| n.try_into().ok()),
+            enemy_score: number("enemy_score").and_then(|n| n.try_into().ok()),
+            credits: number("credits").and_then(|n| n.try_into().ok()),
+            health: number("health").and_then(|n| n.try_into().ok()),
+            armor: number("armor").and_then(|n| n.try_into().ok()),
+            weapon: read("weapon_name")
+                .and_then(Reading::text)
+                .and_then(|text| Weapon::match_string(text.to_string()))
+                .map(|(weapon, _)| weapon),
+            abilities: AbilityCharges {
+                c: number("ability_c_charges").and_then(|n| n.try_into().ok()),
+                q: number("ability_q_charges").and_then(|n| n.try_into().ok()),
+                e: number("ability_e_charges").and_then(|n| n.try_into().ok()),
+                ultimate_points: number("ultimate_points").and_then(|n| n.try_into().ok()),
+            },
+        })
+    }
+
+    fn output(&self, data: &GameData) -> String {
+        match data {
+            GameData::Valorant(hud) => format!(
+                "round time: {}\tscore: {}-{}\tcredits: {}\thealth: {}/{}\tweapon: {}\tabilities: {} {} {} {}",
+                show(&hud.round_time),
+                show(&hud.ally_score),
+                show(&hud.enemy_score),
+                show(&hud.credits),
+                show(&hud.health),
+                show(&hud.armor),
+                show(&hud.weapon),
+                show(&hud.abilities.c),
+                show(&hud.abilities.q),
+                show(&hud.abilities.e),
+                show(&hud.abilities.ultimate_points),
+            ),
+            _ => String::new(),
+        }
+    }
+}
+
+/// Reads the round timer, which shows `m:ss` and switches to seconds with
+/// decimals for the last ten seconds.
+fn parse_round_time(text: &str) -> Option<f64> {
+    match text.split_once(':') {
+        Some((minutes, seconds)) => {
+            let minutes: u32 = minutes.parse().ok()?;
+            let seconds: u32 = seconds.parse().ok()?;
+            if seconds >= 60 {
+                return None;
+            }
+
+            Some(f64::from(minutes * 60 + seconds))
+        }
+        None => text
+            .parse()
+            .ok()
+            .filter(|seconds: &f64| (0. ..10.).contains(seconds)),
+    }
+}
+
+/// What the Valorant HUD showed in a single frame. Values that could not be
+/// read are `None`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ValorantHud {
+    /// Seconds left in the current phase of the round.
+    pub round_time: Option<f64>,
+    pub ally_score: Option<u32>,
+    pub enemy_score: Option<u32>,
+    pub credits: Option<u32>,
+    pub health: Option<u32>,
+    pub armor: Option<u32>,
+    pub weapon: Option<Weapon>,
+    pub abilities: AbilityCharges,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AbilityCharges {
+    pub c: Option<u8>,
+    pub q: Option<u8>,
+    pub e: Option<u8>,
+    pub ultimate_points: Option<u8>,
+}
+
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, PartialEq, Eq, Sequence, Serialize)]
+pub enum Weapon {
+    Knife,
+    Classic,
+    Shorty,
+    Frenzy,
+    Ghost,
+    Sheriff,
+    Stinger,
+    Spectre,
+    Bucky,
+    Judge,
+    Bulldog,
+    Guardian,
+    Phantom,
+    Vandal,
+    Marshal,
+    Outlaw,
+    Operator,
+    Ares,
+    Odin,
+}
+
+impl Weapon {
+    pub fn match_string(s: String) -> Option<(Weapon, f64)> {
+        games::closest_variant(&s)
+    }
+}
+
+impl fmt::Display for Weapon {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Weapon::Knife => "Knife",
+            Weapon::Classic => "Classic",
+            Weapon::Shorty => "Shorty",
+            Weapon::Frenzy => "Frenzy",
+            Weapon::Ghost => "Ghost",
+            Weapon::Sheriff => "Sheriff",
+            Weapon::Stinger => "Stinger",
+            Weapon::Spectre => "Spectre",
+            Weapon::Bucky => "Bucky",
+            Weapon::Judge => "Judge",
+            Weapon::Bulldog => "Bulldog",
+            Weapon::Guardian => "Guardian",
+            Weapon::Phantom => "Phantom",
+            Weapon::Vandal => "Vandal",
+            Weapon::Marshal => "Marshal",
+            Weapon::Outlaw => "Outlaw",
+            Weapon::Operator => "Operator",
+            Weapon::Ares => "Ares",
+            Weapon::Odin => "Odin",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_times_read_minutes_and_seconds() {
+        assert_eq!(parse_round_time("1:45"), Some(105.));
+        assert_eq!(parse_round_time("0:09"), Some(9.));
+        assert_eq!(parse_round_time("1:60"), None);
+        assert_eq!(parse_round_time("1:4a"), None);
+    }
+
+    #[test]
+    fn round_times_under_ten_seconds_are_decimal() {
+        assert_eq!(parse_round_time("9.52"), Some(9.52));
+        assert_eq!(parse_round_time("0.5"), Some(0.5));
+        assert_eq!(parse_round_time("12.5"), None);
+        assert_eq!(parse_round_time("-1.5"), None);
+        assert_eq!(parse_round_time("abc"), None);
+    }
+
+    #[test]
+    fn weapons_show_their_names() {
+        assert_eq!(Weapon::Vandal.to_string(), "Vandal");
+    }
+}
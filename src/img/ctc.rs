@@ -0,0 +1,368 @@
+use std::collections::HashMap;
+
+/// Probability below which a class isn't followed by beam search. Classes
+/// this unlikely can't lift a prefix into the beam.
+const MIN_BEAM_PROBABILITY: f64 = 1e-4;
+
+/// Probability of every class at each step of a model's output. Class 0 is
+/// the CTC blank, class `i` is `alphabet[i - 1]`.
+pub type Probabilities = Vec<Vec<f32>>;
+
+pub fn softmax(scores: &[f32]) -> Vec<f32> {
+    let max = scores.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+    let exp: Vec<f32> = scores.iter().map(|score| (score - max).exp()).collect();
+    let sum: f32 = exp.iter().sum();
+
+    exp.into_iter().map(|e| e / sum).collect()
+}
+
+/// Takes the most likely class at each step, dropping blanks and repeats.
+/// The confidence is the geometric mean of the chosen classes' probabilities.
+pub fn greedy(probabilities: &[Vec<f32>], alphabet: &[String]) -> (String, f64) {
+    let mut text = String::new();
+    let mut log_probability = 0.;
+    let mut previous = 0;
+
+    for step in probabilities {
+        let (class, probability) = step
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .map_or((0, 1.), |(class, probability)| (class, *probability));
+
+        log_probability += f64::from(probability.max(f32::MIN_POSITIVE)).ln();
+        if class != 0 && class != previous {
+            if let Some(label) = alphabet.get(class - 1) {
+                text.push_str(label);
+            }
+        }
+        previous = class;
+    }
+
+    (text, per_step(log_probability, probabilities.len()))
+}
+
+/// Turns a log probability over `steps` steps into a geometric mean
+/// probability per step, so scores don't depend on the output length.
+fn per_step(log_probability: f64, steps: usize) -> f64 {
+    if steps == 0 {
+        0.
+    } else {
+        (log_probability / steps as f64).exp()
+    }
+}
+
+fn log_add(a: f64, b: f64) -> f64 {
+    if a == f64::NEG_INFINITY {
+        return b;
+    }
+    if b == f64::NEG_INFINITY {
+        return a;
+    }
+
+    a.max(b) + (-(a - b).abs()).exp().ln_1p()
+}
+
+/// Keeps the `width` likeliest prefixes at each step, each scored by the
+/// probability of every alignment spelling it, and returns the best one.
+/// The confidence is that probability as a geometric mean per step, like
+/// the lexicon's scores.
+pub fn beam_search(probabilities: &[Vec<f32>], alphabet: &[String], width: usize) -> (String, f64) {
+    // log probabilities of each prefix ending in a blank and in a label
+    let mut beams: HashMap<Vec<usize>, (f64, f64)> =
+        HashMap::from([(Vec::new(), (0., f64::NEG_INFINITY))]);
+
+    for step in probabilities {
+        let log_step: Vec<f64> = step
+            .iter()
+            .map(|p| f64::from(p.max(f32::MIN_POSITIVE)).ln())
+            .collect();
+        let mut next: HashMap<Vec<usize>, (f64, f64)> = HashMap::new();
+
+        for (prefix, (blank, label)) in &beams {
+            let total = log_add(*blank, *label);
+
+            let entry = next
+                .entry(prefix.clone())
+                .or_insert((f64::NEG_INFINITY, f64::NEG_INFINITY));
+            entry.0 = log_add(entry.0, total + log_step[0]);
+
+            for (class, probability) in log_step.iter().enumerate().skip(1) {
+                if *probability < MIN_BEAM_PROBABILITY.ln() {
+                    continue;
+                }
+
+                let mut extended = prefix.clone();
+                extended.push(class);
+
+                if prefix.last() == Some(&class) {
+                    // a repeat only spells a new label after a blank
+                    let entry = next
+                        .entry(extended)
+                        .or_insert((f64::NEG_INFINITY, f64::NEG_INFINITY));
+                    entry.1 = log_add(entry.1, blank + probability);
+
+                    let entry = next
+                        .entry(prefix.clone())
+                        .or_insert((f64::NEG_INFINITY, f64::NEG_INFINITY));
+                    entry.1 = log_add(entry.1, label + probability);
+                } else {
+                    let entry = next
+                        .entry(extended)
+                        .or_insert((f64::NEG_INFINITY, f64::NEG_INFINITY));
+                    entry.1 = log_add(entry.1, total + probability);
+                }
+            }
+        }
+
+        let mut ranked: Vec<(Vec<usize>, (f64, f64))> = next.into_iter().collect();
+        ranked.sort_by(|a, b| log_add(b.1 .0, b.1 .1).total_cmp(&log_add(a.1 .0, a.1 .1)));
+        ranked.truncate(width.max(1));
+        beams = ranked.into_iter().collect();
+    }
+
+    let (labels, log_probability) = beams
+        .into_iter()
+        .map(|(labels, (blank, label))| (labels, log_add(blank, label)))
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .unwrap_or((Vec::new(), f64::NEG_INFINITY));
+
+    let text = labels
+        .iter()
+        .filter_map(|class| alphabet.get(class - 1))
+        .map(String::as_str)
+        .collect();
+
+    (text, per_step(log_probability, probabilities.len()))
+}
+
+/// Log probability of the output spelling `labels`, summed over every
+/// alignment with the CTC forward algorithm.
+fn log_probability(log_probabilities: &[Vec<f64>], labels: &[usize]) -> f64 {
+    if log_probabilities.is_empty() {
+        return f64::NEG_INFINITY;
+    }
+
+    // labels with a blank before, between and after them
+    let mut extended = vec![0; labels.len() * 2 + 1];
+    for (i, label) in labels.iter().enumerate() {
+        extended[i * 2 + 1] = *label;
+    }
+
+    let mut alpha = vec![f64::NEG_INFINITY; extended.len()];
+    alpha[0] = log_probabilities[0][0];
+    if extended.len() > 1 {
+        alpha[1] = log_probabilities[0][extended[1]];
+    }
+
+    for step in &log_probabilities[1..] {
+        let mut next = vec![f64::NEG_INFINITY; extended.len()];
+        for (s, label) in extended.iter().enumerate() {
+            let mut total = alpha[s];
+            if s >= 1 {
+                total = log_add(total, alpha[s - 1]);
+            }
+            // a label can follow the one before the blank unless they're the same
+            if s >= 2 && *label != 0 && *label != extended[s - 2] {
+                total = log_add(total, alpha[s - 2]);
+            }
+            next[s] = total + step[*label];
+        }
+        alpha = next;
+    }
+
+    let last = extended.len() - 1;
+    if last == 0 {
+        alpha[0]
+    } else {
+        log_add(alpha[last], alpha[last - 1])
+    }
+}
+
+/// The words one vocabulary region can show, spelled in the model's classes.
+pub struct Lexicon {
+    /// Each word with every casing of it the model can spell.
+    words: Vec<(String, Vec<Vec<usize>>)>,
+}
+
+/// Spells `words` in the classes of `alphabet`. Whitespace the model has no
+/// class for is left out, words it can't spell otherwise are skipped.
+pub fn lexicon(words: &[String], alphabet: &[String]) -> Lexicon {
+    let classes: HashMap<char, usize> = alphabet
+        .iter()
+        .enumerate()
+        .filter_map(|(i, label)| {
+            let mut chars = label.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Some((c, i + 1)),
+                _ => None,
+            }
+        })
+        .collect();
+
+    let spell = |word: &str| -> Option<Vec<usize>> {
+        word.chars()
+            .filter(|c| !c.is_whitespace() || classes.contains_key(c))
+            .map(|c| classes.get(&c).copied())
+            .collect()
+    };
+
+    let mut spelled = Vec::new();
+    for word in words {
+        let mut spellings: Vec<Vec<usize>> = Vec::new();
+        for casing in [word.clone(), word.to_uppercase(), word.to_lowercase()] {
+            if let Some(labels) = spell(&casing) {
+                if !labels.is_empty() && !spellings.contains(&labels) {
+                    spellings.push(labels);
+                }
+            }
+        }
+
+        if spellings.is_empty() {
+            warn!("the model can't spell {}, it won't be recognized", word);
+        } else {
+            spelled.push((word.clone(), spellings));
+        }
+    }
+
+    Lexicon { words: spelled }
+}
+
+impl Lexicon {
+    /// Scores every word against the model's output, best first. A word's
+    /// score is the per step probability of the output spelling it in any
+    /// of its casings.
+    pub fn rank(&self, probabilities: &[Vec<f32>]) -> Vec<(String, f64)> {
+        let log_probabilities: Vec<Vec<f64>> = probabilities
+            .iter()
+            .map(|step| {
+                step.iter()
+                    .map(|p| f64::from(p.max(f32::MIN_POSITIVE)).ln())
+                    .collect()
+            })
+            .collect();
+
+        let mut ranked: Vec<(String, f64)> = self
+            .words
+            .iter()
+            .map(|(word, spellings)| {
+                let log_probability = spellings
+                    .iter()
+                    .map(|labels| log_probability(&log_probabilities, labels))
+                    .fold(f64::NEG_INFINITY, log_add);
+
+                (word.clone(), per_step(log_probability, probabilities.len()))
+            })
+            .collect();
+
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+        ranked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alphabet() -> Vec<String> {
+        ["a", "b", "c"]
+            .iter()
+            .map(|label| label.to_string())
+            .collect()
+    }
+
+    fn ln(probabilities: &[Vec<f32>]) -> Vec<Vec<f64>> {
+        probabilities
+            .iter()
+            .map(|step| step.iter().map(|p| f64::from(*p).ln()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn log_probability_sums_every_alignment() {
+        // classes are blank, a, b, c
+        let probabilities = vec![vec![0.4, 0.3, 0.3, 0.], vec![0.4, 0.3, 0.3, 0.]];
+
+        // "a" is spelled by "a-", "-a" and "aa"
+        let a = log_probability(&ln(&probabilities), &[1]).exp();
+        assert!((a - (0.3 * 0.4 + 0.4 * 0.3 + 0.3 * 0.3)).abs() < 1e-6);
+
+        // "aa" needs a blank between the labels, which two steps can't fit
+        let aa = log_probability(&ln(&probabilities), &[1, 1]);
+        assert_eq!(aa, f64::NEG_INFINITY);
+
+        let ab = log_probability(&ln(&probabilities), &[1, 2]).exp();
+        assert!((ab - 0.3 * 0.3).abs() < 1e-6);
+
+        let empty = log_probability(&ln(&probabilities), &[]).exp();
+        assert!((empty - 0.4 * 0.4).abs() < 1e-6);
+    }
+
+    #[test]
+    fn log_probability_of_no_output() {
+        assert_eq!(log_probability(&[], &[1]), f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn rank_orders_words_best_first() {
+        let probabilities = vec![
+            vec![0.1, 0.1, 0.7, 0.1],
+            vec![0.6, 0.1, 0.2, 0.1],
+            vec![0.1, 0.1, 0.1, 0.7],
+        ];
+        let words = ["ab", "bc", "c"].map(String::from);
+        let ranked = lexicon(&words, &alphabet()).rank(&probabilities);
+
+        let order: Vec<&str> = ranked.iter().map(|(word, _)| word.as_str()).collect();
+        assert_eq!(order, ["bc", "c", "ab"]);
+
+        let expected = log_probability(&ln(&probabilities), &[2, 3]);
+        assert!((ranked[0].1 - (expected / 3.).exp()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rank_scores_any_casing() {
+        let alphabet: Vec<String> = ["A", "B"].iter().map(|label| label.to_string()).collect();
+        let probabilities = vec![vec![0.1, 0.8, 0.1], vec![0.1, 0.1, 0.8]];
+        let words = ["ab".to_string()];
+
+        let ranked = lexicon(&words, &alphabet).rank(&probabilities);
+        assert_eq!(ranked[0].0, "ab");
+        assert!(ranked[0].1 > 0.5);
+    }
+
+    #[test]
+    fn lexicon_skips_words_it_cant_spell() {
+        let words = ["ab", "xyz"].map(String::from);
+        let ranked = lexicon(&words, &alphabet()).rank(&[vec![0.25; 4]]);
+
+        assert_eq!(ranked.len(), 1);
+        assert_eq!(ranked[0].0, "ab");
+    }
+
+    #[test]
+    fn greedy_drops_blanks_and_repeats() {
+        let probabilities = vec![
+            vec![0.1, 0.9, 0., 0.],
+            vec![0.1, 0.9, 0., 0.],
+            vec![0.9, 0.1, 0., 0.],
+            vec![0.1, 0.9, 0., 0.],
+        ];
+
+        assert_eq!(greedy(&probabilities, &alphabet()).0, "aa");
+    }
+
+    #[test]
+    fn beam_search_finds_text_greedy_misses() {
+        // blank is likeliest at each step, but "a" is likelier over all
+        // of its alignments than the empty text
+        let probabilities = vec![vec![0.4, 0.35, 0.25, 0.], vec![0.4, 0.35, 0.25, 0.]];
+
+        assert_eq!(greedy(&probabilities, &alphabet()).0, "");
+
+        let (text, confidence) = beam_search(&probabilities, &alphabet(), 10);
+        assert_eq!(text, "a");
+        let a = log_probability(&ln(&probabilities), &[1]);
+        assert!((confidence - (a / 2.).exp()).abs() < 1e-6);
+    }
+}
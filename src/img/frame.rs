@@ -1,23 +1,28 @@
+use crate::{
+    detect::FrameKind,
+    error::{Result, StreamCvError},
+    roi::{self, RegionOfInterest, RegionOfInterestList},
+};
 use chrono::{DateTime, Utc};
 use opencv::{
     core::{
         add_weighted, bitwise_and, bitwise_not, Mat, Point as OpenCVPoint, Range, Rect_, Scalar,
         Size as OpenCVSize, ToInputArray, ToInputOutputArray, ToOutputArray, UMat, UMatUsageFlags,
-        Vector, BORDER_DEFAULT,
+        BORDER_DEFAULT,
     },
-    dnn,
     imgproc::{
         bilateral_filter, canny, cvt_color, dilate as dilate_image, get_structuring_element,
-        put_text, rectangle, threshold, COLOR_BGR2GRAY, COLOR_GRAY2BGR, COLOR_GRAY2RGB,
-        FONT_HERSHEY_SIMPLEX, MORPH_DILATE, THRESH_BINARY,
+        put_text, rectangle, resize, threshold, COLOR_BGR2GRAY, COLOR_GRAY2BGR, COLOR_GRAY2RGB,
+        FONT_HERSHEY_SIMPLEX, INTER_CUBIC, MORPH_DILATE, THRESH_BINARY,
     },
     photo::{detail_enhance, inpaint, INPAINT_TELEA},
     prelude::*,
 };
-use std::sync::Arc;
-use tokio::sync::Mutex;
 
-use crate::roi::{RegionOfInterest, RegionOfInterestList};
+use super::{
+    ocr::OcrEngine,
+    preprocess::{self, Graphs},
+};
 
 type Size = OpenCVSize;
 type Point = OpenCVPoint;
@@ -26,8 +31,21 @@ type Point = OpenCVPoint;
 pub struct Frame {
     pub mat: Mat,
     pub processed_mat: UMat,
+    /// Where the image's top left corner lies in the video frame, which is
+    /// only decoded as far as the regions reach.
+    pub offset: Point,
+    /// Whose regions `results` holds.
+    pub kind: FrameKind,
     pub num: i64,
+    /// Position among the frames sent to the pipeline, without gaps.
+    pub seq: i64,
+    /// Presentation timestamp in the time base of the video stream.
+    pub pts: Option<i64>,
+    /// Seconds from the start of the source video, derived from `pts` or
+    /// estimated from the frame rate when the stream has none.
+    pub timestamp: Option<f64>,
     pub text: String,
+    /// Wall-clock time the frame came out of the decoder.
     pub start_date: DateTime<Utc>,
     pub end_date: Option<DateTime<Utc>>,
     pub results: RegionOfInterestList,
@@ -56,297 +74,368 @@ impl Ord for Frame {
     }
 }
 
+/// Crops `rect` out of `mat` without copying it.
+pub fn crop_region(mat: &UMat, rect: Rect_<i32>) -> Result<UMat> {
+    let cropped = mat
+        .col_range(&Range::new(rect.x, rect.x + rect.width)?)?
+        .row_range(&Range::new(rect.y, rect.y + rect.height)?)?;
+
+    Ok(cropped)
+}
+
 #[allow(unused)]
 impl Frame {
     pub fn set_start_date(&mut self, date: DateTime<Utc>) {
         self.start_date = date;
     }
 
-    pub async fn convert_to_gray(mut self) -> Frame {
+    pub async fn convert_to_gray(mut self) -> Result<Frame> {
         cvt_color(
-            &self.processed_mat.input_array().unwrap(),
-            &mut self.processed_mat.output_array().unwrap(),
+            &self.processed_mat.input_array()?,
+            &mut self.processed_mat.output_array()?,
             COLOR_BGR2GRAY,
             0,
-        )
-        .unwrap();
+        )?;
 
-        self
+        Ok(self)
     }
 
-    pub async fn convert_to_bgr(mut self) -> Frame {
+    pub async fn convert_to_bgr(mut self) -> Result<Frame> {
         cvt_color(
-            &self.processed_mat.input_array().unwrap(),
-            &mut self.processed_mat.output_array().unwrap(),
+            &self.processed_mat.input_array()?,
+            &mut self.processed_mat.output_array()?,
             COLOR_GRAY2BGR,
             0,
-        )
-        .unwrap();
+        )?;
 
-        self
+        Ok(self)
     }
 
-    pub async fn convert_to_rgb(mut self) -> Frame {
+    pub async fn convert_to_rgb(mut self) -> Result<Frame> {
         cvt_color(
-            &self.processed_mat.input_array().unwrap(),
-            &mut self.processed_mat.output_array().unwrap(),
+            &self.processed_mat.input_array()?,
+            &mut self.processed_mat.output_array()?,
             COLOR_GRAY2RGB,
             0,
-        )
-        .unwrap();
+        )?;
 
-        self
+        Ok(self)
     }
 
-    pub async fn detail_enhance(&mut self, sigma_s: f32, sigma_r: f32) -> Frame {
+    pub async fn detail_enhance(&mut self, sigma_s: f32, sigma_r: f32) -> Result<Frame> {
         detail_enhance(
-            &self.processed_mat.input_array().unwrap(),
-            &mut self.processed_mat.output_array().unwrap(),
+            &self.processed_mat.input_array()?,
+            &mut self.processed_mat.output_array()?,
             sigma_s,
             sigma_r,
-        )
-        .unwrap();
+        )?;
 
-        self.to_owned()
+        Ok(self.to_owned())
     }
 
-    pub async fn bilateral_filter(&mut self, d: i32, sigma_color: f64, sigma_space: f64) -> Frame {
+    pub async fn bilateral_filter(
+        &mut self,
+        d: i32,
+        sigma_color: f64,
+        sigma_space: f64,
+    ) -> Result<Frame> {
         let mut filtered = UMat::new(UMatUsageFlags::USAGE_DEFAULT);
 
-        match bilateral_filter(
-            &self.processed_mat.input_array().unwrap(),
-            &mut filtered.output_array().unwrap(),
+        bilateral_filter(
+            &self.processed_mat.input_array()?,
+            &mut filtered.output_array()?,
             d,
             sigma_color,
             sigma_space,
             BORDER_DEFAULT,
-        ) {
-            Ok(_) => {
-                self.processed_mat = filtered;
-                self.clone()
-            }
-            Err(err) => {
-                error!("error applying bilateral filter\t{}", err.message);
-                self.clone()
-            }
-        }
+        )?;
+        self.processed_mat = filtered;
+
+        Ok(self.clone())
     }
 
-    pub async fn dilate(mut self, size: Size, iterations: i32, point: Point) -> Frame {
+    pub async fn dilate(mut self, size: Size, iterations: i32, point: Point) -> Result<Frame> {
         dilate_image(
-            &self.processed_mat.input_array().unwrap(),
-            &mut self.processed_mat.output_array().unwrap(),
-            &get_structuring_element(MORPH_DILATE, size, Point { x: -1, y: -1 }).unwrap(),
+            &self.processed_mat.input_array()?,
+            &mut self.processed_mat.output_array()?,
+            &get_structuring_element(MORPH_DILATE, size, Point { x: -1, y: -1 })?,
             point,
             iterations,
             BORDER_DEFAULT,
             Scalar::new(0.0, 0.0, 0.0, 0.0),
-        )
-        .unwrap();
+        )?;
 
-        self
+        Ok(self)
     }
 
-    pub async fn canny(mut self) -> Frame {
+    pub async fn canny(mut self) -> Result<Frame> {
         let mut canny_mat = UMat::new(UMatUsageFlags::USAGE_DEFAULT);
-        canny(&self.processed_mat, &mut canny_mat, 100.0, 100.0, 3, false).unwrap();
+        canny(&self.processed_mat, &mut canny_mat, 100.0, 100.0, 3, false)?;
 
         self.processed_mat = canny_mat;
 
-        self
+        Ok(self)
     }
 
-    pub async fn add_weighted(mut self, overlay: UMat, alpha: f64, beta: f64, gamma: f64) -> Frame {
+    pub async fn add_weighted(
+        mut self,
+        overlay: UMat,
+        alpha: f64,
+        beta: f64,
+        gamma: f64,
+    ) -> Result<Frame> {
         add_weighted(
-            &self.processed_mat.input_array().unwrap(),
+            &self.processed_mat.input_array()?,
             alpha,
-            &overlay.input_array().unwrap(),
+            &overlay.input_array()?,
             beta,
             gamma,
-            &mut self.processed_mat.output_array().unwrap(),
+            &mut self.processed_mat.output_array()?,
             0,
-        )
-        .unwrap();
+        )?;
 
-        self
+        Ok(self)
     }
 
-    pub async fn inpaint(mut self, mask: UMat) -> Frame {
+    pub async fn inpaint(mut self, mask: UMat) -> Result<Frame> {
         inpaint(
-            &self.processed_mat.input_array().unwrap(),
+            &self.processed_mat.input_array()?,
             &mask,
-            &mut self.processed_mat.output_array().unwrap(),
+            &mut self.processed_mat.output_array()?,
             3.0,
             INPAINT_TELEA,
+        )?;
+
+        Ok(self)
+    }
+
+    /// Where `region`, placed in video frame pixels, lies in the image.
+    fn image_rect(&self, region: &RegionOfInterest) -> Rect_<i32> {
+        Rect_::new(
+            region.x - self.offset.x,
+            region.y - self.offset.y,
+            region.width,
+            region.height,
         )
-        .unwrap();
+    }
 
-        self
+    /// Fails if the region doesn't lie entirely within the image.
+    fn check_bounds(&self, region: &RegionOfInterest) -> Result<Rect_<i32>> {
+        let (cols, rows) = (self.processed_mat.cols(), self.processed_mat.rows());
+        let rect = self.image_rect(region);
+
+        if rect.x < 0
+            || rect.y < 0
+            || rect.width <= 0
+            || rect.height <= 0
+            || rect.x + rect.width > cols
+            || rect.y + rect.height > rows
+        {
+            return Err(StreamCvError::RegionOutOfBounds {
+                name: region.name.clone(),
+                rect: (rect.x, rect.y, rect.width, rect.height),
+                frame: (cols, rows),
+            });
+        }
+
+        Ok(rect)
     }
 
-    pub fn extract_roi(&self, region: RegionOfInterest) -> UMat {
-        let mut cropped = self
-            .processed_mat
-            .clone()
-            .col_range(&Range::new(region.x, region.x + region.width).unwrap())
-            .unwrap();
+    pub fn extract_roi(&self, region: RegionOfInterest) -> Result<UMat> {
+        let rect = self.check_bounds(&region)?;
+
+        crop_region(&self.processed_mat, rect)
+    }
 
-        cropped = cropped
-            .row_range(&Range::new(region.y, region.y + region.height).unwrap())
-            .unwrap();
+    /// Copies `region` into a frame of its own, so ops run on the crop leave
+    /// this frame untouched.
+    pub fn crop(&self, region: &RegionOfInterest) -> Result<Frame> {
+        let mut processed_mat = UMat::new(UMatUsageFlags::USAGE_DEFAULT);
+        self.extract_roi(region.clone())?
+            .copy_to(&mut processed_mat)?;
+
+        Ok(Frame {
+            mat: Mat::default(),
+            processed_mat,
+            offset: Point::new(region.x, region.y),
+            kind: self.kind,
+            num: self.num,
+            seq: self.seq,
+            pts: self.pts,
+            timestamp: self.timestamp,
+            text: String::new(),
+            start_date: self.start_date,
+            end_date: None,
+            results: roi::new_region_list(),
+        })
+    }
 
-        cropped
+    /// Crops `region` and runs its processing graph on the crop, the one
+    /// named by its `graph` option if it has one.
+    pub async fn preprocessed_crop(
+        &self,
+        region: &RegionOfInterest,
+        graphs: &Graphs,
+    ) -> Result<UMat> {
+        let graph = region
+            .options
+            .get("graph")
+            .and_then(|name| graphs.get(name))
+            .unwrap_or(&region.preprocess);
+        let crop = preprocess::run(graph, self.crop(region)?).await?;
+
+        Ok(crop.processed_mat)
     }
 
-    pub async fn adjust_contrast(&mut self, amount: f64) -> Frame {
+    pub async fn adjust_contrast(&mut self, amount: f64) -> Result<Frame> {
         let base_mat = self.processed_mat.clone();
-        base_mat
-            .convert_to(
-                &mut self.processed_mat.output_array().unwrap(),
-                -1,
-                amount,
-                amount * -2.,
-            )
-            .unwrap();
-
-        self.to_owned()
+        base_mat.convert_to(
+            &mut self.processed_mat.output_array()?,
+            -1,
+            amount,
+            amount * -2.,
+        )?;
+
+        Ok(self.to_owned())
     }
 
-    pub async fn adjust_brightness(mut self, amount: f64) -> Frame {
+    pub async fn adjust_brightness(mut self, amount: f64) -> Result<Frame> {
         let base_mat = self.processed_mat.clone();
-        base_mat
-            .convert_to(
-                &mut self.processed_mat.output_array().unwrap(),
-                -1,
-                1.,
-                amount,
-            )
-            .unwrap();
-
-        self
+        base_mat.convert_to(&mut self.processed_mat.output_array()?, -1, 1., amount)?;
+
+        Ok(self)
     }
 
-    pub async fn threshold(mut self) -> Frame {
+    pub async fn threshold(mut self, thresh: f64, max: f64) -> Result<Frame> {
         threshold(
-            &self.processed_mat.input_array().unwrap(),
-            &mut self.processed_mat.output_array().unwrap(),
-            120.0,
-            255.0,
+            &self.processed_mat.input_array()?,
+            &mut self.processed_mat.output_array()?,
+            thresh,
+            max,
             THRESH_BINARY,
-        )
-        .unwrap();
+        )?;
 
-        self
+        Ok(self)
     }
 
-    pub async fn bitwise_not(mut self, mask: Option<UMat>) -> Frame {
+    /// Scales the image by `factor` in both directions.
+    pub async fn resize(mut self, factor: f64) -> Result<Frame> {
+        let mut resized = UMat::new(UMatUsageFlags::USAGE_DEFAULT);
+
+        resize(
+            &self.processed_mat,
+            &mut resized,
+            Size::default(),
+            factor,
+            factor,
+            INTER_CUBIC,
+        )?;
+        self.processed_mat = resized;
+
+        Ok(self)
+    }
+
+    pub async fn bitwise_not(mut self, mask: Option<UMat>) -> Result<Frame> {
         if let Some(mask) = mask {
             bitwise_not(
-                &self.processed_mat.input_array().unwrap(),
-                &mut self.processed_mat.output_array().unwrap(),
+                &self.processed_mat.input_array()?,
+                &mut self.processed_mat.output_array()?,
                 &mask,
-            )
-            .unwrap();
+            )?;
         } else {
-            let empty = Mat::zeros(
-                self.processed_mat.rows(),
-                self.processed_mat.cols(),
-                self.processed_mat.typ(),
-            )
-            .unwrap();
-
+            // an empty mask inverts every pixel, a zeroed one would invert none
             bitwise_not(
-                &self.processed_mat.input_array().unwrap(),
-                &mut self.processed_mat.output_array().unwrap(),
-                &empty,
-            )
-            .unwrap();
+                &self.processed_mat.input_array()?,
+                &mut self.processed_mat.output_array()?,
+                &Mat::default(),
+            )?;
         }
 
-        self
+        Ok(self)
     }
 
-    pub async fn bitwise_and(mut self, mask: UMat) -> Frame {
-        let empty = Mat::zeros(
-            self.processed_mat.rows(),
-            self.processed_mat.cols(),
-            self.processed_mat.typ(),
-        )
-        .unwrap();
+    /// Keeps the pixels under `mask`, blacking out the rest.
+    pub async fn bitwise_and(mut self, mask: UMat) -> Result<Frame> {
+        // a fresh destination starts out black where the mask is unset
+        let mut masked = UMat::new(UMatUsageFlags::USAGE_DEFAULT);
 
         bitwise_and(
-            &self.processed_mat.input_array().unwrap(),
-            &empty.input_array().unwrap(),
-            &mut self.processed_mat.output_array().unwrap(),
+            &self.processed_mat.input_array()?,
+            &self.processed_mat.input_array()?,
+            &mut masked.output_array()?,
             &mask,
-        )
-        .unwrap();
+        )?;
+        self.processed_mat = masked;
 
-        self
+        Ok(self)
     }
 
-    pub async fn list_text_recognition<'f>(
+    pub async fn list_text_recognition(
         &mut self,
         region_list: RegionOfInterestList,
-        shared_recognizer: &Arc<Mutex<dnn::TextRecognitionModel>>,
-    ) {
-        let array: Vector<Rect_<i32>> = region_list.vec_of_rects();
-        let mut results: Vector<String> = Vector::new();
-
-        let recognizer = shared_recognizer.lock().await;
-        recognizer
-            .recognize_1(&self.processed_mat, &array, &mut results)
-            .unwrap();
-
-        for (i, region) in region_list.iter().enumerate() {
-            let (_, mut new_region) = region.clone();
-            new_region.set_result(results.get(i).unwrap());
+        engine: &dyn OcrEngine,
+        graphs: &Graphs,
+    ) -> Result<()> {
+        let mut crops = Vec::new();
+        for (_, region) in region_list.iter() {
+            crops.push(self.preprocessed_crop(&region, graphs).await?);
+        }
+
+        let results = engine.recognize_regions(&crops, &region_list)?;
+
+        for ((_, mut new_region), recognition) in region_list.iter().zip(results) {
+            new_region.set_result(recognition.text);
+            new_region.confidence = recognition.confidence;
+            new_region.runner_up = recognition.runner_up;
             self.results.add_region(new_region);
         }
+
+        Ok(())
     }
 
     pub async fn text_recognition(
         mut self,
         mut region: RegionOfInterest,
-        shared_recognizer: &Arc<Mutex<dnn::TextRecognitionModel>>,
-    ) -> Frame {
-        let mat = self.extract_roi(region.clone());
+        engine: &dyn OcrEngine,
+        graphs: &Graphs,
+    ) -> Result<Frame> {
+        let mat = self.preprocessed_crop(&region, graphs).await?;
 
-        let recognizer = shared_recognizer.lock().await;
-        let recognition_result = recognizer.recognize(&mat).unwrap();
+        let recognition = engine.recognize(&mat, &region)?;
 
-        region.set_result(recognition_result);
+        region.set_result(recognition.text);
+        region.confidence = recognition.confidence;
+        region.runner_up = recognition.runner_up;
 
         self.results.add_region(region);
 
-        self
+        Ok(self)
     }
 
     pub async fn add_result(mut self, region: RegionOfInterest) {
         self.results.add_region(region);
     }
 
-    pub fn highlight_regions(&mut self) {
+    pub fn highlight_regions(&mut self) -> Result<()> {
         for (_, region) in self.results.iter() {
-            let rect = Rect_::new(region.x, region.y, region.width, region.height);
+            let rect = self.image_rect(&region);
 
             rectangle(
-                &mut self.processed_mat.input_output_array().unwrap(),
+                &mut self.processed_mat.input_output_array()?,
                 rect,
                 Scalar::new(0., 255., 0.0, 1.0),
                 1,
                 0,
                 0,
-            )
-            .unwrap();
+            )?;
 
-            if region.result.is_some() {
+            if let Some(result) = region.result.as_ref() {
                 put_text(
-                    &mut self.processed_mat.input_output_array().unwrap(),
-                    region.result.as_ref().unwrap(),
+                    &mut self.processed_mat.input_output_array()?,
+                    result,
                     opencv::core::Point_ {
-                        x: region.x + region.width + 5,
-                        y: region.y + region.height,
+                        x: rect.x + rect.width + 5,
+                        y: rect.y + rect.height,
                     },
                     FONT_HERSHEY_SIMPLEX,
                     0.75,
@@ -354,9 +443,10 @@ impl Frame {
                     1,
                     0,
                     false,
-                )
-                .unwrap();
+                )?;
             }
         }
+
+        Ok(())
     }
 }
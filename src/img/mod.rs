@@ -1,50 +1,166 @@
+use clap::ArgEnum;
+use std::fmt;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
-use std::path::PathBuf;
+use std::path::Path;
 
 use opencv::core::{Scalar, Size};
 use opencv::dnn;
 use opencv::prelude::*;
 use opencv::types::VectorOfString;
 
+use crate::error::{Result, StreamCvError};
+use model::ModelDefinition;
+
+pub mod ctc;
 pub mod frame;
+pub mod model;
+pub mod ocr;
+pub mod preprocess;
+pub mod template;
+
+/// Where text recognition runs.
+#[derive(ArgEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnnBackend {
+    /// The fastest backend available: CUDA, then OpenVINO, then the CPU.
+    Auto,
+    Cpu,
+    Openvino,
+    Cuda,
+}
+
+impl fmt::Display for DnnBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DnnBackend::Auto => "auto",
+            DnnBackend::Cpu => "cpu",
+            DnnBackend::Openvino => "openvino",
+            DnnBackend::Cuda => "cuda",
+        };
+
+        write!(f, "{}", name)
+    }
+}
 
-pub fn make_text_recognizer() -> dnn::TextRecognitionModel {
-    debug!("loading models for text recognition");
-    let rec_model_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-        .join("models")
-        .join("crnn_cs.onnx");
-    let voc_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
-        .join("models")
-        .join("alphabet_94.txt");
+impl DnnBackend {
+    fn backend_and_target(self) -> (dnn::Backend, dnn::Target) {
+        match self {
+            DnnBackend::Auto | DnnBackend::Cpu => (
+                dnn::Backend::DNN_BACKEND_OPENCV,
+                dnn::Target::DNN_TARGET_CPU,
+            ),
+            DnnBackend::Openvino => (
+                dnn::Backend::DNN_BACKEND_INFERENCE_ENGINE,
+                dnn::Target::DNN_TARGET_CPU,
+            ),
+            DnnBackend::Cuda => (dnn::Backend::DNN_BACKEND_CUDA, dnn::Target::DNN_TARGET_CUDA),
+        }
+    }
+
+    /// Whether opencv was built with this backend and, for CUDA, whether
+    /// there is a device to run on.
+    fn is_available(self) -> Result<bool> {
+        let (backend, target) = self.backend_and_target();
+        if !dnn::get_available_targets(backend)?
+            .iter()
+            .any(|available| available == target)
+        {
+            return Ok(false);
+        }
+
+        if self == DnnBackend::Cuda {
+            return Ok(opencv::core::get_cuda_enabled_device_count()? > 0);
+        }
+
+        Ok(true)
+    }
+}
 
-    let mut recognizer =
-        dnn::TextRecognitionModel::from_file(rec_model_path.to_str().unwrap(), "").unwrap();
+/// Resolves `requested` to a backend that can actually be used.
+///
+/// `Auto` picks the fastest available one, any other choice fails if it
+/// isn't available.
+pub fn select_backend(requested: DnnBackend) -> Result<DnnBackend> {
+    let backend = match requested {
+        DnnBackend::Auto => [DnnBackend::Cuda, DnnBackend::Openvino]
+            .into_iter()
+            .find(|candidate| candidate.is_available().unwrap_or(false))
+            .unwrap_or(DnnBackend::Cpu),
+        requested if requested.is_available()? => requested,
+        requested => {
+            return Err(StreamCvError::Config(format!(
+                "the {} dnn backend isn't available in this build of opencv or on this machine",
+                requested
+            )))
+        }
+    };
 
+    info!("running text recognition on the {} backend", backend);
+
+    Ok(backend)
+}
+
+fn model_error(path: &Path, reason: impl ToString) -> StreamCvError {
+    StreamCvError::ModelLoad {
+        path: path.to_path_buf(),
+        reason: reason.to_string(),
+    }
+}
+
+/// Loads the recognition model described by `definition` and its
+/// vocabulary, resolving relative paths against `model_dir`.
+pub fn make_text_recognizer(
+    definition: &ModelDefinition,
+    model_dir: &Path,
+    backend: DnnBackend,
+) -> Result<(dnn::TextRecognitionModel, Vec<String>)> {
+    let rec_model_path = model_dir.join(&definition.path);
+    let voc_path = model_dir.join(&definition.vocabulary);
+    debug!(
+        "loading text recognition model {}",
+        rec_model_path.display()
+    );
+
+    if !rec_model_path.is_file() {
+        return Err(model_error(&rec_model_path, "file not found"));
+    }
+
+    let model_file = rec_model_path
+        .to_str()
+        .ok_or_else(|| model_error(&rec_model_path, "path is not valid utf-8"))?;
+    let mut recognizer = dnn::TextRecognitionModel::from_file(model_file, "")
+        .map_err(|err| model_error(&rec_model_path, err.message))?;
+
+    let (dnn_backend, dnn_target) = backend.backend_and_target();
     recognizer
-        .set_preferable_target(dnn::Target::DNN_TARGET_CUDA)
-        .unwrap()
-        .set_preferable_backend(dnn::Backend::DNN_BACKEND_CUDA)
-        .unwrap();
+        .set_preferable_target(dnn_target)?
+        .set_preferable_backend(dnn_backend)?;
 
     // Load vocabulary
-    let mut vocabulary = VectorOfString::new();
-    let voc_file = BufReader::new(File::open(voc_path).unwrap());
-    for voc_line in voc_file.lines() {
-        vocabulary.push(&voc_line.unwrap());
+    let voc_file =
+        BufReader::new(File::open(&voc_path).map_err(|err| model_error(&voc_path, err))?);
+    let vocabulary = voc_file
+        .lines()
+        .collect::<std::io::Result<Vec<String>>>()
+        .map_err(|err| model_error(&voc_path, err))?;
+
+    if vocabulary.is_empty() {
+        return Err(model_error(&voc_path, "vocabulary is empty"));
     }
 
     // Parameters for Recognition
-    let rec_scale = 1. / 127.5;
-    let rec_mean = Scalar::from((127.5, 127.5, 127.5));
-    let rec_input_size = Size::new(100, 32);
+    let (width, height) = definition.input_size;
+    let rec_mean = Scalar::from((definition.mean, definition.mean, definition.mean));
     recognizer
-        .set_vocabulary(&vocabulary)
-        .unwrap()
-        .set_decode_type("CTC-greedy")
-        .unwrap()
-        .set_input_params(rec_scale, rec_input_size, rec_mean, false, false)
-        .unwrap();
+        .set_vocabulary(&VectorOfString::from_iter(vocabulary.iter().cloned()))?
+        .set_decode_type(&definition.decode_type)?
+        .set_input_params(
+            definition.scale,
+            Size::new(width, height),
+            rec_mean,
+            false,
+            false,
+        )?;
 
-    recognizer
+    Ok((recognizer, vocabulary))
 }
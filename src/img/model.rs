@@ -0,0 +1,241 @@
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    error::{Result, StreamCvError},
+    reading::Vocabularies,
+    roi::{RegionOfInterest, RegionOfInterestList},
+    Config,
+};
+
+use super::ocr::{self, OcrEngine};
+
+/// Name of the model regions use when neither they nor `--model` pick one.
+pub const DEFAULT_MODEL: &str = "crnn";
+
+pub type SharedEngine = Arc<dyn OcrEngine>;
+
+/// How to load and feed a text recognition model. Relative paths are
+/// resolved against the model directory.
+///
+/// ```toml
+/// [digits]
+/// path = "crnn_digits.onnx"
+/// vocabulary = "digits.txt"
+/// input_size = [100, 32]
+/// mean = 127.5
+/// scale = 0.00784313725490196
+/// # or "CTC-prefix-beam-search", keeping `beam_width` prefixes
+/// decode_type = "CTC-greedy"
+/// beam_width = 10
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelDefinition {
+    pub path: PathBuf,
+    pub vocabulary: PathBuf,
+    /// Width and height the model expects its input scaled to.
+    #[serde(default = "default_input_size")]
+    pub input_size: (i32, i32),
+    #[serde(default = "default_mean")]
+    pub mean: f64,
+    #[serde(default = "default_scale")]
+    pub scale: f64,
+    #[serde(default = "default_decode_type")]
+    pub decode_type: String,
+    #[serde(default = "default_beam_width")]
+    pub beam_width: usize,
+}
+
+fn default_input_size() -> (i32, i32) {
+    (100, 32)
+}
+
+fn default_mean() -> f64 {
+    127.5
+}
+
+fn default_scale() -> f64 {
+    1. / 127.5
+}
+
+fn default_decode_type() -> String {
+    "CTC-greedy".to_string()
+}
+
+fn default_beam_width() -> usize {
+    10
+}
+
+impl Default for ModelDefinition {
+    fn default() -> Self {
+        ModelDefinition {
+            path: PathBuf::from("crnn_cs.onnx"),
+            vocabulary: PathBuf::from("alphabet_94.txt"),
+            input_size: default_input_size(),
+            mean: default_mean(),
+            scale: default_scale(),
+            decode_type: default_decode_type(),
+            beam_width: default_beam_width(),
+        }
+    }
+}
+
+/// The engine a region is read with, picked with its `engine` option.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum EngineChoice {
+    /// A CRNN model, by name.
+    Crnn(String),
+    /// Tesseract, by language.
+    Tesseract(String),
+}
+
+impl EngineChoice {
+    fn for_region(region: &RegionOfInterest, default_model: &str) -> Result<EngineChoice> {
+        let option = |name: &str| region.options.get(name).map(String::as_str);
+
+        match option("engine").unwrap_or("crnn") {
+            "crnn" => Ok(EngineChoice::Crnn(
+                option("model").unwrap_or(default_model).to_string(),
+            )),
+            "tesseract" => Ok(EngineChoice::Tesseract(
+                option("language").unwrap_or("eng").to_string(),
+            )),
+            engine => Err(StreamCvError::Config(format!(
+                "region {} uses engine {}, expected crnn or tesseract",
+                region.name, engine
+            ))),
+        }
+    }
+
+    fn key(&self) -> String {
+        match self {
+            EngineChoice::Crnn(model) => model.clone(),
+            EngineChoice::Tesseract(language) => format!("tesseract/{}", language),
+        }
+    }
+}
+
+/// Every engine the regions use, each loaded once and shared between workers.
+#[derive(Clone)]
+pub struct ModelRegistry {
+    engines: HashMap<String, SharedEngine>,
+    /// Model regions that don't name one are read with.
+    default_model: String,
+    default_engine: String,
+}
+
+impl ModelRegistry {
+    /// Name of the engine `region` is read with.
+    pub fn engine_name(&self, region: &RegionOfInterest) -> String {
+        EngineChoice::for_region(region, &self.default_model)
+            .map_or_else(|_| self.default_engine.clone(), |choice| choice.key())
+    }
+
+    /// The engine called `name`, or the default engine.
+    pub fn get(&self, name: &str) -> &SharedEngine {
+        self.engines
+            .get(name)
+            .unwrap_or_else(|| &self.engines[&self.default_engine])
+    }
+}
+
+/// Directory relative model paths are resolved against: `--model-dir`, else
+/// `models` next to the executable, else `models` in the working directory.
+pub fn model_dir(config: &Config) -> PathBuf {
+    if let Some(dir) = &config.model_dir {
+        return dir.clone();
+    }
+
+    env::current_exe()
+        .ok()
+        .and_then(|exe| exe.parent().map(|dir| dir.join("models")))
+        .filter(|dir| dir.is_dir())
+        .unwrap_or_else(|| PathBuf::from("models"))
+}
+
+/// Reads model definitions from a TOML file of named tables.
+pub fn load_definitions(path: &Path) -> Result<HashMap<String, ModelDefinition>> {
+    let invalid = |reason: String| {
+        StreamCvError::Config(format!(
+            "invalid model definitions in {}: {}",
+            path.display(),
+            reason
+        ))
+    };
+
+    let contents = fs::read_to_string(path).map_err(|err| invalid(err.to_string()))?;
+    toml::from_str(&contents).map_err(|err| invalid(err.to_string()))
+}
+
+/// Loads the default model and every engine a region asks for.
+pub fn registry(
+    config: &Config,
+    regions: &RegionOfInterestList,
+    vocabularies: &Vocabularies,
+) -> Result<ModelRegistry> {
+    let mut definitions = HashMap::new();
+    definitions.insert(DEFAULT_MODEL.to_string(), ModelDefinition::default());
+    if let Some(path) = &config.models {
+        definitions.extend(load_definitions(path)?);
+    }
+
+    let default_choice = EngineChoice::Crnn(config.model.clone());
+    let mut choices = vec![default_choice.clone()];
+    for (_, region) in regions.iter() {
+        let choice = EngineChoice::for_region(&region, &config.model)?;
+        if !choices.contains(&choice) {
+            choices.push(choice);
+        }
+    }
+
+    let dir = model_dir(config);
+    let mut backend = None;
+
+    let mut engines: HashMap<String, SharedEngine> = HashMap::new();
+    for choice in choices {
+        let engine: SharedEngine = match &choice {
+            EngineChoice::Crnn(model) => {
+                let definition = definitions.get(model).ok_or_else(|| {
+                    StreamCvError::Config(format!("model {} isn't defined", model))
+                })?;
+                let beam_width = match definition.decode_type.as_str() {
+                    "CTC-greedy" => None,
+                    "CTC-prefix-beam-search" => Some(definition.beam_width),
+                    other => {
+                        return Err(StreamCvError::Config(format!(
+                            "model {} has unknown decode type {}",
+                            model, other
+                        )))
+                    }
+                };
+                let backend = match backend {
+                    Some(backend) => backend,
+                    None => *backend.insert(super::select_backend(config.dnn_backend)?),
+                };
+
+                info!("loading text recognition model {}", model);
+                let (recognizer, vocabulary) =
+                    super::make_text_recognizer(definition, &dir, backend)?;
+                Arc::new(ocr::crnn(recognizer, vocabulary, beam_width, vocabularies))
+            }
+            EngineChoice::Tesseract(language) => {
+                info!("loading tesseract for {}", language);
+                Arc::new(ocr::tesseract(config.tessdata.as_deref(), language)?)
+            }
+        };
+
+        engines.insert(choice.key(), engine);
+    }
+
+    Ok(ModelRegistry {
+        engines,
+        default_model: config.model.clone(),
+        default_engine: default_choice.key(),
+    })
+}
@@ -0,0 +1,229 @@
+use opencv::{
+    core::{Mat, UMat, Vector},
+    dnn,
+    imgproc::{cvt_color, COLOR_BGR2GRAY},
+    prelude::*,
+};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+};
+use tesseract::Tesseract;
+
+use crate::{
+    error::{Result, StreamCvError},
+    reading::{Candidate, Vocabularies},
+    roi::{RegionOfInterest, RegionOfInterestList, RegionOfInterestType},
+};
+
+use super::ctc::{self, Lexicon, Probabilities};
+
+/// Text read from a region.
+#[derive(Debug, Clone)]
+pub struct Recognition {
+    pub text: String,
+    /// How sure the engine is of the text, from 0 to 1, `None` if it can't
+    /// tell.
+    pub confidence: Option<f64>,
+    /// The next best word, for regions decoded against a vocabulary.
+    pub runner_up: Option<Candidate>,
+}
+
+/// Something that can read the text in a region of a frame.
+pub trait OcrEngine: Send + Sync {
+    /// Reads the text in `image`, the crop of a single region.
+    fn recognize(&self, image: &UMat, region: &RegionOfInterest) -> Result<Recognition>;
+
+    /// Reads every region in `regions` from its crop in `crops`, which are
+    /// in the list's order.
+    fn recognize_regions(
+        &self,
+        crops: &[UMat],
+        regions: &RegionOfInterestList,
+    ) -> Result<Vec<Recognition>> {
+        crops
+            .iter()
+            .zip(regions.iter())
+            .map(|(crop, (_, region))| self.recognize(crop, &region))
+            .collect()
+    }
+}
+
+/// OpenCV's CRNN text recognition model.
+///
+/// Its output is decoded here rather than by OpenCV so the probability of
+/// the decoded text is known. Enum regions are decoded against their
+/// vocabulary, other regions greedily or, with a beam width, by prefix beam
+/// search.
+pub struct CrnnEngine {
+    model: Mutex<dnn::TextRecognitionModel>,
+    alphabet: Vec<String>,
+    lexicons: HashMap<String, Lexicon>,
+    beam_width: Option<usize>,
+}
+
+pub fn crnn(
+    model: dnn::TextRecognitionModel,
+    alphabet: Vec<String>,
+    beam_width: Option<usize>,
+    vocabularies: &Vocabularies,
+) -> CrnnEngine {
+    let lexicons = vocabularies
+        .iter()
+        .map(|(name, words)| (name.clone(), ctc::lexicon(words, &alphabet)))
+        .collect();
+
+    CrnnEngine {
+        model: Mutex::new(model),
+        alphabet,
+        lexicons,
+        beam_width,
+    }
+}
+
+impl CrnnEngine {
+    /// Runs the model on `image`.
+    fn probabilities(&self, image: &UMat) -> Result<Probabilities> {
+        let mut outputs = Vector::<Mat>::new();
+        self.model
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .predict(image, &mut outputs)?;
+
+        let output = outputs.get(0)?;
+        let scores = output.data_typed::<f32>()?;
+        let classes = self.alphabet.len() + 1;
+        if scores.len() % classes != 0 {
+            return Err(StreamCvError::Ocr(format!(
+                "model output of {} values doesn't fit a vocabulary of {} classes",
+                scores.len(),
+                classes
+            )));
+        }
+
+        Ok(scores.chunks(classes).map(ctc::softmax).collect())
+    }
+}
+
+impl OcrEngine for CrnnEngine {
+    fn recognize(&self, image: &UMat, region: &RegionOfInterest) -> Result<Recognition> {
+        let probabilities = self.probabilities(image)?;
+
+        let lexicon = match &region.roi_type {
+            RegionOfInterestType::Enum(vocabulary) => self.lexicons.get(vocabulary),
+            _ => None,
+        };
+        if let Some(lexicon) = lexicon {
+            let mut ranked = lexicon.rank(&probabilities).into_iter();
+            if let Some((text, confidence)) = ranked.next() {
+                return Ok(Recognition {
+                    text,
+                    confidence: Some(confidence),
+                    runner_up: ranked
+                        .next()
+                        .map(|(text, confidence)| Candidate { text, confidence }),
+                });
+            }
+        }
+
+        let (text, confidence) = match self.beam_width {
+            Some(width) => ctc::beam_search(&probabilities, &self.alphabet, width),
+            None => ctc::greedy(&probabilities, &self.alphabet),
+        };
+
+        Ok(Recognition {
+            text,
+            confidence: Some(confidence),
+            runner_up: None,
+        })
+    }
+}
+
+/// libtesseract, reading each region as a single line of text.
+///
+/// Regions can restrict the characters it reads with a `whitelist` option,
+/// numeric regions are restricted to digits by default.
+pub struct TesseractEngine {
+    // tesseract's api is consumed and handed back by every call, so it's
+    // taken out while in use and rebuilt if a call fails
+    api: Mutex<Option<Tesseract>>,
+    datapath: Option<PathBuf>,
+    language: String,
+}
+
+pub fn tesseract(datapath: Option<&Path>, language: &str) -> Result<TesseractEngine> {
+    let engine = TesseractEngine {
+        api: Mutex::new(None),
+        datapath: datapath.map(Path::to_path_buf),
+        language: language.to_string(),
+    };
+
+    // fail at startup rather than on the first frame if the language is missing
+    *engine.api.lock().unwrap_or_else(PoisonError::into_inner) = Some(engine.init()?);
+
+    Ok(engine)
+}
+
+fn ocr_error(err: impl ToString) -> StreamCvError {
+    StreamCvError::Ocr(err.to_string())
+}
+
+impl TesseractEngine {
+    fn init(&self) -> Result<Tesseract> {
+        let datapath = self.datapath.as_ref().and_then(|path| path.to_str());
+
+        Tesseract::new(datapath, Some(&self.language)).map_err(|err| StreamCvError::ModelLoad {
+            path: self
+                .datapath
+                .clone()
+                .unwrap_or_else(|| PathBuf::from("tessdata"))
+                .join(format!("{}.traineddata", self.language)),
+            reason: err.to_string(),
+        })
+    }
+}
+
+impl OcrEngine for TesseractEngine {
+    fn recognize(&self, image: &UMat, region: &RegionOfInterest) -> Result<Recognition> {
+        let mut gray = Mat::default();
+        if image.channels() == 1 {
+            image.copy_to(&mut gray)?;
+        } else {
+            cvt_color(image, &mut gray, COLOR_BGR2GRAY, 0)?;
+        }
+
+        let whitelist = match (region.options.get("whitelist"), &region.roi_type) {
+            (Some(whitelist), _) => whitelist.as_str(),
+            (None, RegionOfInterestType::Digits) => "0123456789",
+            (None, RegionOfInterestType::Integer) => "-0123456789",
+            _ => "",
+        };
+
+        let mut api = self.api.lock().unwrap_or_else(PoisonError::into_inner);
+        let tesseract = match api.take() {
+            Some(tesseract) => tesseract,
+            None => self.init()?,
+        };
+
+        let mut tesseract = tesseract
+            .set_variable("tessedit_pageseg_mode", "7")
+            .map_err(ocr_error)?
+            .set_variable("tessedit_char_whitelist", whitelist)
+            .map_err(ocr_error)?
+            .set_frame(gray.data_bytes()?, gray.cols(), gray.rows(), 1, gray.cols())
+            .map_err(ocr_error)?
+            .recognize()
+            .map_err(ocr_error)?;
+
+        let text = tesseract.get_text().map_err(ocr_error)?;
+        let confidence = f64::from(tesseract.mean_text_conf().max(0)) / 100.;
+        *api = Some(tesseract);
+
+        Ok(Recognition {
+            text: text.trim().to_string(),
+            confidence: Some(confidence),
+            runner_up: None,
+        })
+    }
+}
@@ -0,0 +1,481 @@
+use opencv::core::{Point, Size};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fs, path::Path};
+
+use crate::{
+    error::{Result, StreamCvError},
+    roi::RegionOfInterestList,
+};
+
+use super::frame::Frame;
+
+/// Name steps use to refer to the region's crop as it was before any step.
+pub const SOURCE: &str = "source";
+
+/// One operation run on a region's crop before it's read.
+///
+/// Steps that combine images name the step whose output they use as
+/// `mask` or `overlay`. Masks have to be single channel.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Step {
+    Gray,
+    /// Back to three channels, which CRNN models expect.
+    Bgr,
+    BilateralFilter {
+        d: i32,
+        sigma_color: f64,
+        sigma_space: f64,
+    },
+    DetailEnhance {
+        sigma_s: f32,
+        sigma_r: f32,
+    },
+    Contrast {
+        amount: f64,
+    },
+    Brightness {
+        amount: f64,
+    },
+    Threshold {
+        #[serde(default = "default_thresh")]
+        thresh: f64,
+        #[serde(default = "default_max")]
+        max: f64,
+    },
+    /// Inverts the pixels under `mask`, or all of them without one.
+    Invert {
+        mask: Option<String>,
+    },
+    /// Keeps the pixels under `mask`, blacking out the rest.
+    BitwiseAnd {
+        mask: String,
+    },
+    /// Blends in `overlay`, which has to match the input's size and channels.
+    AddWeighted {
+        overlay: String,
+        alpha: f64,
+        beta: f64,
+        #[serde(default)]
+        gamma: f64,
+    },
+    /// Paints over the pixels under `mask` from their surroundings.
+    Inpaint {
+        mask: String,
+    },
+    Upscale {
+        factor: f64,
+    },
+    Dilate {
+        #[serde(default = "default_kernel")]
+        size: i32,
+        #[serde(default = "default_iterations")]
+        iterations: i32,
+    },
+    Canny,
+}
+
+fn default_thresh() -> f64 {
+    120.
+}
+
+fn default_max() -> f64 {
+    255.
+}
+
+fn default_kernel() -> i32 {
+    3
+}
+
+fn default_iterations() -> i32 {
+    1
+}
+
+/// A step in a graph. Its input is the previous step's output unless it
+/// names another step, or `source`, as `input`. Only named steps can be
+/// referred to, and only by the steps after them.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Node {
+    pub name: Option<String>,
+    pub input: Option<String>,
+    #[serde(flatten)]
+    pub step: Step,
+}
+
+/// Steps run on a region's crop, the last one's output is what gets read.
+///
+/// ```toml
+/// white_text = [
+///     { op = "gray", name = "gray" },
+///     { op = "threshold", thresh = 200, name = "bright" },
+///     { op = "bitwise_and", input = "source", mask = "bright" },
+///     { op = "upscale", factor = 2 },
+/// ]
+/// ```
+pub type Graph = Vec<Node>;
+
+/// Graphs regions can pick by name with a `graph` option.
+pub type Graphs = HashMap<String, Graph>;
+
+impl From<Step> for Node {
+    fn from(step: Step) -> Self {
+        Node {
+            name: None,
+            input: None,
+            step,
+        }
+    }
+}
+
+/// The graph regions get when they don't declare one, which used to run on
+/// every whole frame.
+pub fn default_graph() -> Graph {
+    vec![
+        Step::BilateralFilter {
+            d: 9,
+            sigma_color: 75.,
+            sigma_space: 75.,
+        }
+        .into(),
+        Step::Contrast { amount: 1.55 }.into(),
+        Step::Brightness { amount: -10. }.into(),
+    ]
+}
+
+pub fn load(path: &Path) -> Result<Graphs> {
+    let invalid = |reason: String| {
+        StreamCvError::Config(format!(
+            "invalid processing graphs in {}: {}",
+            path.display(),
+            reason
+        ))
+    };
+
+    let contents = fs::read_to_string(path).map_err(|err| invalid(err.to_string()))?;
+    toml::from_str(&contents).map_err(|err| invalid(err.to_string()))
+}
+
+/// What validation knows about a step's output, relative to the crop.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Shape {
+    channels: i32,
+    scale: f64,
+}
+
+impl Step {
+    /// Images the step reads besides its input.
+    fn references(&self) -> Vec<&str> {
+        match self {
+            Step::Invert { mask: Some(mask) }
+            | Step::BitwiseAnd { mask }
+            | Step::Inpaint { mask } => vec![mask],
+            Step::AddWeighted { overlay, .. } => vec![overlay],
+            _ => vec![],
+        }
+    }
+
+    /// Checks the step's parameters and inputs, returning the shape of its
+    /// output.
+    fn check(
+        &self,
+        input: Shape,
+        outputs: &HashMap<&str, Shape>,
+    ) -> std::result::Result<Shape, String> {
+        let mask = |name: &str| {
+            let mask = outputs[name];
+            if mask.channels != 1 {
+                Err(format!("mask {} has {} channels", name, mask.channels))
+            } else if mask.scale != input.scale {
+                Err(format!("mask {} isn't the size of the input", name))
+            } else {
+                Ok(input)
+            }
+        };
+
+        match self {
+            Step::Gray if input.channels != 3 => Err("input is already gray".to_string()),
+            Step::Gray => Ok(Shape {
+                channels: 1,
+                ..input
+            }),
+            Step::Bgr if input.channels != 1 => Err("input isn't gray".to_string()),
+            Step::Bgr => Ok(Shape {
+                channels: 3,
+                ..input
+            }),
+            Step::DetailEnhance { .. } if input.channels != 3 => {
+                Err("needs a three channel input".to_string())
+            }
+            Step::Upscale { factor } if !(factor.is_finite() && *factor > 0.) => {
+                Err(format!("can't scale by {}", factor))
+            }
+            Step::Upscale { factor } => Ok(Shape {
+                scale: input.scale * factor,
+                ..input
+            }),
+            Step::Dilate { size, iterations } if *size <= 0 || *iterations <= 0 => {
+                Err("needs a positive size and number of iterations".to_string())
+            }
+            Step::Canny => Ok(Shape {
+                channels: 1,
+                ..input
+            }),
+            Step::Invert { mask: Some(name) }
+            | Step::BitwiseAnd { mask: name }
+            | Step::Inpaint { mask: name } => mask(name),
+            Step::AddWeighted { overlay, .. } if outputs[overlay.as_str()] != input => {
+                Err(format!(
+                    "overlay {} doesn't match the input's size and channels",
+                    overlay
+                ))
+            }
+            _ => Ok(input),
+        }
+    }
+
+    async fn apply(&self, mut frame: Frame, outputs: &HashMap<&str, Frame>) -> Result<Frame> {
+        let image = |name: &str| outputs[name].processed_mat.clone();
+
+        let frame = match self {
+            Step::Gray => frame.convert_to_gray().await?,
+            Step::Bgr => frame.convert_to_bgr().await?,
+            Step::BilateralFilter {
+                d,
+                sigma_color,
+                sigma_space,
+            } => {
+                frame
+                    .bilateral_filter(*d, *sigma_color, *sigma_space)
+                    .await?
+            }
+            Step::DetailEnhance { sigma_s, sigma_r } => {
+                frame.detail_enhance(*sigma_s, *sigma_r).await?
+            }
+            Step::Contrast { amount } => frame.adjust_contrast(*amount).await?,
+            Step::Brightness { amount } => frame.adjust_brightness(*amount).await?,
+            Step::Threshold { thresh, max } => frame.threshold(*thresh, *max).await?,
+            Step::Invert { mask } => frame.bitwise_not(mask.as_deref().map(image)).await?,
+            Step::BitwiseAnd { mask } => frame.bitwise_and(image(mask)).await?,
+            Step::AddWeighted {
+                overlay,
+                alpha,
+                beta,
+                gamma,
+            } => {
+                frame
+                    .add_weighted(image(overlay), *alpha, *beta, *gamma)
+                    .await?
+            }
+            Step::Inpaint { mask } => frame.inpaint(image(mask)).await?,
+            Step::Upscale { factor } => frame.resize(*factor).await?,
+            Step::Dilate { size, iterations } => {
+                frame
+                    .dilate(Size::new(*size, *size), *iterations, Point::new(-1, -1))
+                    .await?
+            }
+            Step::Canny => frame.canny().await?,
+        };
+
+        Ok(frame)
+    }
+}
+
+/// Checks that every step's references exist and its inputs fit it.
+pub fn validate(graph: &Graph) -> std::result::Result<(), String> {
+    let source = Shape {
+        channels: 3,
+        scale: 1.,
+    };
+    let mut outputs = HashMap::from([(SOURCE, source)]);
+    let mut previous = source;
+
+    for (i, node) in graph.iter().enumerate() {
+        let label = match &node.name {
+            Some(name) => name.clone(),
+            None => format!("step {}", i + 1),
+        };
+
+        let input = node.input.as_deref();
+        for reference in input.into_iter().chain(node.step.references()) {
+            if !outputs.contains_key(reference) {
+                return Err(format!(
+                    "{} refers to {}, which isn't an earlier step",
+                    label, reference
+                ));
+            }
+        }
+
+        let input = input.map_or(previous, |name| outputs[name]);
+        previous = node
+            .step
+            .check(input, &outputs)
+            .map_err(|reason| format!("{}: {}", label, reason))?;
+
+        if let Some(name) = node.name.as_deref() {
+            if outputs.insert(name, previous).is_some() {
+                return Err(format!("{} is defined more than once", name));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Validates `graphs` and the graphs of `regions`, and that every graph a
+/// region picks by name exists.
+pub fn check(graphs: &Graphs, regions: &RegionOfInterestList) -> Result<()> {
+    for (name, graph) in graphs {
+        validate(graph)
+            .map_err(|reason| StreamCvError::Config(format!("graph {}: {}", name, reason)))?;
+    }
+
+    for (name, region) in regions.iter() {
+        match region.options.get("graph") {
+            Some(graph) if !graphs.contains_key(graph) => {
+                return Err(StreamCvError::Config(format!(
+                    "region {} uses graph {}, which isn't defined",
+                    name, graph
+                )));
+            }
+            Some(_) => {}
+            None => {
+                validate(&region.preprocess).map_err(|reason| {
+                    StreamCvError::Config(format!("region {} preprocessing: {}", name, reason))
+                })?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs `graph` on `source`, returning the last step's output.
+pub async fn run(graph: &Graph, source: Frame) -> Result<Frame> {
+    let mut outputs = HashMap::from([(SOURCE, source.clone())]);
+    let mut previous = source;
+
+    for node in graph {
+        let input = match node.input.as_deref() {
+            Some(name) => outputs[name].clone(),
+            None => previous,
+        };
+
+        previous = node.step.apply(input, &outputs).await?;
+
+        if let Some(name) = node.name.as_deref() {
+            outputs.insert(name, previous.clone());
+        }
+    }
+
+    Ok(previous)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roi::{self, StreamResolution};
+
+    fn graph(steps: &str) -> Graph {
+        let mut graphs: Graphs = toml::from_str(&format!("graph = [{}]", steps)).unwrap();
+        graphs.remove("graph").unwrap()
+    }
+
+    fn invalid(steps: &str) -> String {
+        validate(&graph(steps)).unwrap_err()
+    }
+
+    #[test]
+    fn valid_graphs_pass() {
+        validate(&default_graph()).unwrap();
+        validate(&graph(
+            r#"
+            { op = "gray", name = "gray" },
+            { op = "threshold", thresh = 200, name = "bright" },
+            { op = "bitwise_and", input = "source", mask = "bright" },
+            { op = "upscale", factor = 2 },
+            "#,
+        ))
+        .unwrap();
+    }
+
+    #[test]
+    fn steps_can_only_refer_to_earlier_ones() {
+        assert!(invalid(
+            r#"
+            { op = "bitwise_and", mask = "bright" },
+            { op = "gray" },
+            { op = "threshold", name = "bright" },
+            "#
+        )
+        .contains("bright, which isn't an earlier step"));
+        assert!(invalid(r#"{ op = "gray", input = "nowhere" }"#).contains("nowhere"));
+    }
+
+    #[test]
+    fn names_are_unique() {
+        assert!(invalid(
+            r#"
+            { op = "gray", name = "step" },
+            { op = "threshold", name = "step" },
+            "#
+        )
+        .contains("defined more than once"));
+    }
+
+    #[test]
+    fn masks_are_single_channel_and_the_input_size() {
+        assert!(invalid(r#"{ op = "invert", mask = "source" }"#).contains("3 channels"));
+        assert!(invalid(
+            r#"
+            { op = "gray", name = "gray" },
+            { op = "upscale", factor = 2 },
+            { op = "bitwise_and", mask = "gray" },
+            "#
+        )
+        .contains("isn't the size of the input"));
+    }
+
+    #[test]
+    fn overlays_match_the_input() {
+        assert!(invalid(
+            r#"
+            { op = "gray", name = "gray" },
+            { op = "bgr" },
+            { op = "add_weighted", overlay = "gray", alpha = 0.5, beta = 0.5 },
+            "#
+        )
+        .contains("doesn't match"));
+    }
+
+    #[test]
+    fn channel_conversions_need_the_right_input() {
+        assert!(invalid(r#"{ op = "gray" }, { op = "gray" }"#).contains("already gray"));
+        assert!(invalid(r#"{ op = "bgr" }"#).contains("isn't gray"));
+        assert!(invalid(
+            r#"{ op = "gray" }, { op = "detail_enhance", sigma_s = 10, sigma_r = 0.15 }"#
+        )
+        .contains("three channel"));
+    }
+
+    #[test]
+    fn parameters_are_checked() {
+        assert!(invalid(r#"{ op = "upscale", factor = 0 }"#).contains("can't scale"));
+        assert!(invalid(r#"{ op = "dilate", size = 0 }"#).contains("positive size"));
+    }
+
+    #[test]
+    fn regions_have_to_pick_defined_graphs() {
+        let mut region =
+            roi::new_region("ammo".to_string(), 0, 0, 10, 10, StreamResolution::HD1080P);
+        region
+            .options
+            .insert("graph".to_string(), "white_text".to_string());
+        let mut regions = roi::new_region_list();
+        regions.add_region(region);
+
+        assert!(check(&Graphs::new(), &regions).is_err());
+
+        let graphs = Graphs::from([("white_text".to_string(), default_graph())]);
+        check(&graphs, &regions).unwrap();
+    }
+}
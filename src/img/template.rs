@@ -0,0 +1,74 @@
+use opencv::{
+    core::{min_max_loc, no_array, Mat, Size, UMat, UMatUsageFlags},
+    imgcodecs::{imread, IMREAD_GRAYSCALE},
+    imgproc::{cvt_color, match_template, resize, COLOR_BGR2GRAY, INTER_AREA, TM_CCOEFF_NORMED},
+    prelude::*,
+};
+use std::path::Path;
+
+use crate::error::{Result, StreamCvError};
+
+/// Score below which a template isn't taken to be in its region.
+pub const DEFAULT_THRESHOLD: f64 = 0.8;
+
+/// A picture of a fixed HUD element, taken at its region's base resolution.
+pub struct Template {
+    image: Mat,
+    threshold: f64,
+}
+
+/// Loads the image at `path` as a template that matches with a score of at
+/// least `threshold`.
+pub fn load(path: &Path, threshold: f64) -> Result<Template> {
+    let invalid = |reason: &str| {
+        StreamCvError::Config(format!(
+            "unable to load template {}: {}",
+            path.display(),
+            reason
+        ))
+    };
+
+    let file = path
+        .to_str()
+        .ok_or_else(|| invalid("path is not valid utf-8"))?;
+    let image = imread(file, IMREAD_GRAYSCALE)?;
+    if image.empty() {
+        return Err(invalid("not a readable image"));
+    }
+
+    Ok(Template { image, threshold })
+}
+
+impl Template {
+    /// Best normalized correlation of the template anywhere in `crop`, with
+    /// the template scaled by `scale` first to the crop's resolution.
+    pub fn score(&self, crop: &UMat, scale: f64) -> Result<f64> {
+        let mut gray = UMat::new(UMatUsageFlags::USAGE_DEFAULT);
+        if crop.channels() == 1 {
+            crop.copy_to(&mut gray)?;
+        } else {
+            cvt_color(crop, &mut gray, COLOR_BGR2GRAY, 0)?;
+        }
+
+        // rounding can leave the scaled template a pixel larger than the crop
+        let size = Size::new(
+            ((self.image.cols() as f64 * scale).round() as i32).clamp(1, gray.cols()),
+            ((self.image.rows() as f64 * scale).round() as i32).clamp(1, gray.rows()),
+        );
+        let mut template = Mat::default();
+        resize(&self.image, &mut template, size, 0., 0., INTER_AREA)?;
+
+        let mut scores = UMat::new(UMatUsageFlags::USAGE_DEFAULT);
+        match_template(&gray, &template, &mut scores, TM_CCOEFF_NORMED, &no_array())?;
+
+        let mut best = 0.;
+        min_max_loc(&scores, None, Some(&mut best), None, None, &no_array())?;
+
+        Ok(best)
+    }
+
+    /// Whether the template is in `crop`.
+    pub fn matches(&self, crop: &UMat, scale: f64) -> Result<bool> {
+        Ok(self.score(crop, scale)? >= self.threshold)
+    }
+}
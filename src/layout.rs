@@ -0,0 +1,582 @@
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::StreamCvError,
+    img::preprocess::{Graph, Graphs},
+    reading::Vocabularies,
+    roi::{
+        self, NormalizedRect, RegionOfInterest, RegionOfInterestList, RegionOfInterestType,
+        StreamResolution,
+    },
+};
+
+/// A set of regions read from a TOML or JSON layout file.
+///
+/// ```toml
+/// base_resolution = { width = 1920, height = 1080 }
+/// # recognition model for regions that don't pick their own
+/// model = "crnn"
+/// # processing graph for regions that don't declare their own, replacing
+/// # the default bilateral filter, contrast and brightness adjustment
+/// graph = "white_text"
+/// # pixels at the base resolution neighbouring regions may share, 0 if unset
+/// overlap_tolerance = 2
+///
+/// [vocabularies]
+/// fire_modes = ["single", "auto", "burst"]
+///
+/// [graphs]
+/// white_text = [
+///     { op = "gray", name = "gray" },
+///     { op = "threshold", thresh = 200, name = "bright" },
+///     { op = "bitwise_and", input = "source", mask = "bright" },
+/// ]
+///
+/// [[regions]]
+/// name = "compass_number"
+/// type = "integer"
+/// options = { min = "0", max = "359" }
+/// rect = { anchor = "top", x = 0.0, y = 0.083333, width = 0.026042, height = 0.02963 }
+///
+/// [[regions]]
+/// name = "fire_mode"
+/// type = { enum = "fire_modes" }
+/// rect = { anchor = "bottom_right", x = 0.05, y = 0.1, width = 0.04, height = 0.02 }
+///
+/// [[regions]]
+/// name = "total_ammo"
+/// pixels = { x = 1720, y = 998, width = 62, height = 30 }
+/// options = { note = "reserve ammo", model = "digits" }
+/// preprocess = [
+///     { op = "gray" },
+///     { op = "upscale", factor = 2 },
+///     { op = "threshold", thresh = 150 },
+///     { op = "bgr" },
+/// ]
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegionLayout {
+    pub base_resolution: StreamResolution,
+    pub model: Option<String>,
+    /// Named graph regions without preprocessing of their own use.
+    pub graph: Option<String>,
+    pub preprocess: Option<Graph>,
+    /// Pixels at the base resolution that regions may overlap by, for rects
+    /// drawn against a shared HUD edge.
+    #[serde(default)]
+    pub overlap_tolerance: i32,
+    /// Words enum regions can read, by vocabulary name.
+    #[serde(default)]
+    pub vocabularies: Vocabularies,
+    /// Processing graphs regions can pick with a `graph` option.
+    #[serde(default)]
+    pub graphs: Graphs,
+    pub regions: Vec<RegionDefinition>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RegionDefinition {
+    pub name: String,
+    #[serde(rename = "type", default = "default_type")]
+    pub roi_type: RegionOfInterestType,
+    pub rect: Option<NormalizedRect>,
+    pub pixels: Option<PixelRect>,
+    pub base_resolution: Option<StreamResolution>,
+    #[serde(default)]
+    pub options: BTreeMap<String, String>,
+    pub preprocess: Option<Graph>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PixelRect {
+    pub x: i32,
+    pub y: i32,
+    pub width: i32,
+    pub height: i32,
+}
+
+fn default_type() -> RegionOfInterestType {
+    RegionOfInterestType::FreeText
+}
+
+#[derive(Debug)]
+pub enum LayoutError {
+    Io(PathBuf, io::Error),
+    UnknownFormat(PathBuf),
+    Toml(toml::de::Error),
+    Json(serde_json::Error),
+    Invalid(Vec<LayoutProblem>),
+}
+
+#[derive(Debug)]
+pub enum LayoutProblem {
+    Empty,
+    InvalidResolution(String),
+    DuplicateName(String),
+    MissingRect(String),
+    AmbiguousRect(String),
+    OutOfBounds(String),
+    Overlap(String, String),
+}
+
+impl fmt::Display for LayoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LayoutError::Io(path, err) => {
+                write!(f, "unable to read layout {}: {}", path.display(), err)
+            }
+            LayoutError::UnknownFormat(path) => write!(
+                f,
+                "unable to read layout {}: expected a .toml or .json file",
+                path.display()
+            ),
+            LayoutError::Toml(err) => write!(f, "invalid layout: {}", err),
+            LayoutError::Json(err) => write!(f, "invalid layout: {}", err),
+            LayoutError::Invalid(problems) => {
+                write!(f, "invalid layout:")?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for LayoutProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LayoutProblem::Empty => write!(f, "layout has no regions"),
+            LayoutProblem::InvalidResolution(name) => {
+                write!(
+                    f,
+                    "{} has a base resolution without a width or height",
+                    name
+                )
+            }
+            LayoutProblem::DuplicateName(name) => {
+                write!(f, "region {} is defined more than once", name)
+            }
+            LayoutProblem::MissingRect(name) => {
+                write!(f, "region {} needs either a rect or pixels", name)
+            }
+            LayoutProblem::AmbiguousRect(name) => {
+                write!(f, "region {} has both a rect and pixels", name)
+            }
+            LayoutProblem::OutOfBounds(name) => {
+                write!(f, "region {} lies outside of the picture", name)
+            }
+            LayoutProblem::Overlap(first, second) => {
+                write!(f, "regions {} and {} overlap", first, second)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LayoutError {}
+
+pub fn load(path: &Path) -> Result<RegionLayout, LayoutError> {
+    let contents =
+        fs::read_to_string(path).map_err(|err| LayoutError::Io(path.to_path_buf(), err))?;
+
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => from_toml(&contents),
+        Some("json") => from_json(&contents),
+        _ => Err(LayoutError::UnknownFormat(path.to_path_buf())),
+    }
+}
+
+pub fn from_toml(contents: &str) -> Result<RegionLayout, LayoutError> {
+    let layout: RegionLayout = toml::from_str(contents).map_err(LayoutError::Toml)?;
+    layout.validate()?;
+
+    Ok(layout)
+}
+
+pub fn from_json(contents: &str) -> Result<RegionLayout, LayoutError> {
+    let layout: RegionLayout = serde_json::from_str(contents).map_err(LayoutError::Json)?;
+    layout.validate()?;
+
+    Ok(layout)
+}
+
+fn is_valid_resolution(res: &StreamResolution) -> bool {
+    res.width > 0 && res.height > 0
+}
+
+/// Whether the rects share more than `tolerance` pixels both across and down.
+fn overlaps(first: &RegionOfInterest, second: &RegionOfInterest, tolerance: i32) -> bool {
+    let across = (first.x + first.width).min(second.x + second.width) - first.x.max(second.x);
+    let down = (first.y + first.height).min(second.y + second.height) - first.y.max(second.y);
+
+    across > tolerance && down > tolerance
+}
+
+impl RegionDefinition {
+    fn to_region(&self, default_res: StreamResolution) -> Option<RegionOfInterest> {
+        let res = self.base_resolution.unwrap_or(default_res);
+
+        let mut region = match (self.rect, self.pixels) {
+            (Some(rect), None) => roi::new_normalized_region(self.name.clone(), rect, res),
+            (None, Some(p)) => roi::new_region(self.name.clone(), p.x, p.y, p.width, p.height, res),
+            _ => return None,
+        };
+
+        region.roi_type = self.roi_type.clone();
+        region.options = self.options.clone();
+        if let Some(preprocess) = &self.preprocess {
+            region.preprocess = preprocess.clone();
+        }
+
+        Some(region)
+    }
+
+    fn is_within_bounds(&self, res: &StreamResolution) -> bool {
+        match (self.rect, self.pixels) {
+            (Some(rect), _) => rect.is_within_picture(),
+            (_, Some(p)) => {
+                p.x >= 0
+                    && p.y >= 0
+                    && p.width > 0
+                    && p.height > 0
+                    && p.x + p.width <= res.width
+                    && p.y + p.height <= res.height
+            }
+            (None, None) => false,
+        }
+    }
+}
+
+impl RegionLayout {
+    pub fn validate(&self) -> Result<(), LayoutError> {
+        let mut problems = Vec::new();
+
+        if !is_valid_resolution(&self.base_resolution) {
+            problems.push(LayoutProblem::InvalidResolution("layout".to_string()));
+        }
+
+        if self.regions.is_empty() {
+            problems.push(LayoutProblem::Empty);
+        }
+
+        let mut names = HashSet::new();
+        for region in &self.regions {
+            if !names.insert(region.name.as_str()) {
+                problems.push(LayoutProblem::DuplicateName(region.name.clone()));
+            }
+
+            let res = region.base_resolution.unwrap_or(self.base_resolution);
+            if !is_valid_resolution(&res) {
+                problems.push(LayoutProblem::InvalidResolution(region.name.clone()));
+                continue;
+            }
+
+            match (region.rect, region.pixels) {
+                (None, None) => problems.push(LayoutProblem::MissingRect(region.name.clone())),
+                (Some(_), Some(_)) => {
+                    problems.push(LayoutProblem::AmbiguousRect(region.name.clone()))
+                }
+                _ if !region.is_within_bounds(&res) => {
+                    problems.push(LayoutProblem::OutOfBounds(region.name.clone()))
+                }
+                _ => {}
+            }
+        }
+
+        // overlaps are only meaningful once every rect resolves cleanly
+        if problems.is_empty() {
+            let resolved: Vec<RegionOfInterest> = self
+                .regions
+                .iter()
+                .filter_map(|region| region.to_region(self.base_resolution))
+                .map(|region| region.resolve(&self.base_resolution))
+                .collect();
+
+            for (i, first) in resolved.iter().enumerate() {
+                for second in resolved.iter().skip(i + 1) {
+                    if overlaps(first, second, self.overlap_tolerance.max(0)) {
+                        problems.push(LayoutProblem::Overlap(
+                            first.name.clone(),
+                            second.name.clone(),
+                        ));
+                    }
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(LayoutError::Invalid(problems))
+        }
+    }
+
+    /// Rejects layouts and regions that pick a named graph and declare their
+    /// own preprocessing at the same time, since only one of them would run.
+    pub fn check_preprocess(&self) -> crate::error::Result<()> {
+        if self.preprocess.is_some() && self.graph.is_some() {
+            return Err(StreamCvError::Config(
+                "the layout has both a graph and preprocessing, pick one".to_string(),
+            ));
+        }
+
+        for region in &self.regions {
+            if region.preprocess.is_some() && region.options.contains_key("graph") {
+                return Err(StreamCvError::Config(format!(
+                    "region {} has both a graph option and preprocessing, pick one",
+                    region.name
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn regions(&self) -> RegionOfInterestList {
+        let mut list = roi::new_region_list();
+
+        for mut region in self.regions.iter().filter_map(|definition| {
+            let mut region = definition.to_region(self.base_resolution)?;
+            if definition.preprocess.is_none() {
+                if let Some(preprocess) = &self.preprocess {
+                    region.preprocess = preprocess.clone();
+                } else if let Some(graph) = &self.graph {
+                    region
+                        .options
+                        .entry("graph".to_string())
+                        .or_insert_with(|| graph.clone());
+                }
+            }
+            Some(region)
+        }) {
+            if let Some(model) = &self.model {
+                region
+                    .options
+                    .entry("model".to_string())
+                    .or_insert_with(|| model.clone());
+            }
+            list.add_region(region);
+        }
+
+        list
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "base_resolution = { width = 1920, height = 1080 }\n";
+
+    fn problems(regions: &str) -> Vec<LayoutProblem> {
+        match from_toml(&format!("{}{}", HEADER, regions)) {
+            Ok(_) => Vec::new(),
+            Err(LayoutError::Invalid(problems)) => problems,
+            Err(err) => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn built_in_layouts_are_valid() {
+        for layout in [
+            include_str!("../layouts/apex.toml"),
+            include_str!("../layouts/valorant.toml"),
+        ] {
+            from_toml(layout).unwrap().check_preprocess().unwrap();
+        }
+    }
+
+    #[test]
+    fn rects_and_pixels_resolve_alike() {
+        let layout = from_toml(&format!(
+            "{}{}",
+            HEADER,
+            r#"
+            overlap_tolerance = 2
+
+            [[regions]]
+            name = "from_rect"
+            rect = { anchor = "bottom_right", x = 0.071875, y = 0.074074, width = 0.032292, height = 0.037037 }
+
+            [[regions]]
+            name = "from_pixels"
+            pixels = { x = 1720, y = 998, width = 62, height = 30 }
+            "#
+        ))
+        .unwrap();
+        let regions = layout.regions();
+
+        let rect = regions.get_value("from_rect".to_string()).unwrap();
+        assert_eq!(
+            (rect.x, rect.y, rect.width, rect.height),
+            (1720, 960, 62, 40)
+        );
+        let pixels = regions.get_value("from_pixels".to_string()).unwrap();
+        assert_eq!(
+            (pixels.x, pixels.y, pixels.width, pixels.height),
+            (1720, 998, 62, 30)
+        );
+    }
+
+    #[test]
+    fn empty_layouts_are_rejected() {
+        assert!(matches!(
+            problems("regions = []").as_slice(),
+            [LayoutProblem::Empty]
+        ));
+    }
+
+    #[test]
+    fn regions_need_exactly_one_rect() {
+        let found = problems(
+            r#"
+            [[regions]]
+            name = "nowhere"
+
+            [[regions]]
+            name = "twice"
+            rect = { x = 0.0, y = 0.0, width = 0.1, height = 0.1 }
+            pixels = { x = 0, y = 0, width = 10, height = 10 }
+            "#,
+        );
+
+        assert!(matches!(
+            found.as_slice(),
+            [LayoutProblem::MissingRect(a), LayoutProblem::AmbiguousRect(b)]
+                if a == "nowhere" && b == "twice"
+        ));
+    }
+
+    #[test]
+    fn names_have_to_be_unique() {
+        let found = problems(
+            r#"
+            [[regions]]
+            name = "ammo"
+            pixels = { x = 0, y = 0, width = 10, height = 10 }
+
+            [[regions]]
+            name = "ammo"
+            pixels = { x = 100, y = 0, width = 10, height = 10 }
+            "#,
+        );
+
+        assert!(matches!(
+            found.as_slice(),
+            [LayoutProblem::DuplicateName(name)] if name == "ammo"
+        ));
+    }
+
+    #[test]
+    fn regions_have_to_fit_the_picture() {
+        let found = problems(
+            r#"
+            [[regions]]
+            name = "past_the_edge"
+            rect = { x = 0.95, y = 0.0, width = 0.1, height = 0.1 }
+            "#,
+        );
+
+        assert!(matches!(
+            found.as_slice(),
+            [LayoutProblem::OutOfBounds(name)] if name == "past_the_edge"
+        ));
+    }
+
+    #[test]
+    fn overlapping_regions_are_rejected() {
+        let found = problems(
+            r#"
+            [[regions]]
+            name = "first"
+            pixels = { x = 0, y = 0, width = 100, height = 100 }
+
+            [[regions]]
+            name = "second"
+            pixels = { x = 50, y = 50, width = 100, height = 100 }
+            "#,
+        );
+
+        assert!(matches!(
+            found.as_slice(),
+            [LayoutProblem::Overlap(a, b)] if a == "first" && b == "second"
+        ));
+    }
+
+    #[test]
+    fn touching_regions_are_allowed() {
+        let found = problems(
+            r#"
+            [[regions]]
+            name = "left"
+            pixels = { x = 0, y = 0, width = 100, height = 100 }
+
+            [[regions]]
+            name = "right"
+            pixels = { x = 100, y = 0, width = 100, height = 100 }
+            "#,
+        );
+
+        assert!(found.is_empty());
+    }
+
+    #[test]
+    fn grazing_regions_need_a_tolerance() {
+        let regions = r#"
+            [[regions]]
+            name = "above"
+            pixels = { x = 0, y = 0, width = 100, height = 100 }
+
+            [[regions]]
+            name = "below"
+            pixels = { x = 0, y = 98, width = 100, height = 100 }
+            "#;
+
+        assert!(matches!(
+            problems(regions).as_slice(),
+            [LayoutProblem::Overlap(_, _)]
+        ));
+        assert!(problems(&format!("overlap_tolerance = 2\n{}", regions)).is_empty());
+        assert_eq!(
+            problems(&format!("overlap_tolerance = 1\n{}", regions)).len(),
+            1
+        );
+    }
+
+    fn check_preprocess(layout: &str, region: &str) -> crate::error::Result<()> {
+        from_toml(&format!(
+            r#"{}{}
+            [[regions]]
+            name = "ammo"
+            pixels = {{ x = 0, y = 0, width = 10, height = 10 }}
+            {}
+            "#,
+            HEADER, layout, region
+        ))
+        .unwrap()
+        .check_preprocess()
+    }
+
+    #[test]
+    fn graphs_and_preprocessing_exclude_each_other() {
+        let graph = r#"graph = "white_text""#;
+        let option = r#"options = { graph = "white_text" }"#;
+        let preprocess = r#"preprocess = [{ op = "gray" }, { op = "bgr" }]"#;
+
+        check_preprocess(graph, "").unwrap();
+        check_preprocess("", option).unwrap();
+        // a region's own preprocessing replaces the layout's graph
+        check_preprocess(graph, preprocess).unwrap();
+
+        assert!(check_preprocess(&format!("{}\n{}", graph, preprocess), "").is_err());
+        assert!(check_preprocess("", &format!("{}\n{}", option, preprocess)).is_err());
+    }
+}
@@ -1,19 +1,30 @@
 use crate::{
-    games::{Game, SupportedGames},
+    error::{FrameErrorPolicy, StreamCvError},
+    games::{self, SupportedGames},
+    img::{preprocess, DnnBackend},
     stream::VideoStream,
 };
 use clap::Parser;
 use opencv::core::{get_num_threads, set_num_threads};
-use std::thread::available_parallelism;
+use std::{
+    collections::HashMap, path::PathBuf, sync::Arc, thread::available_parallelism, time::Instant,
+};
+use tokio::select;
 
 extern crate pretty_env_logger;
 #[macro_use]
 extern crate log;
 
+mod detect;
+pub mod error;
 pub mod games;
 mod img;
+mod layout;
 pub mod pipeline;
+mod reading;
+mod reorder;
 mod roi;
+pub mod sink;
 mod state;
 pub mod stream;
 
@@ -25,6 +36,18 @@ pub struct Config {
     #[clap(short, long)]
     pub url: String,
 
+    /// Game whose HUD is read
+    #[clap(short, long, arg_enum, default_value = "apex")]
+    pub game: SupportedGames,
+
+    /// Work out which game is on screen from its HUD, pausing while none is
+    #[clap(long)]
+    pub detect_game: bool,
+
+    /// Seconds between checks of which game is on screen with --detect-game
+    #[clap(long, default_value_t = 5.)]
+    pub detect_interval: f64,
+
     /// Show processed frames in a window
     #[clap(short, long)]
     pub show_frames: bool,
@@ -35,46 +58,319 @@ pub struct Config {
     #[clap(long, default_value_t = 1)]
     pub num_libav_threads: i32,
 
-    #[clap(long, default_value_t = 2)]
-    pub process_frame_rate: i64,
+    /// Where to run text recognition
+    #[clap(long, arg_enum, default_value = "auto")]
+    pub dnn_backend: DnnBackend,
+
+    /// Directory model and vocabulary paths are relative to [default: models next to the executable, else ./models]
+    #[clap(long)]
+    pub model_dir: Option<PathBuf>,
+
+    /// Model definitions file (TOML) adding to or replacing the built-in crnn model
+    #[clap(long)]
+    pub models: Option<PathBuf>,
+
+    /// Tesseract data directory [default: tesseract's own]
+    #[clap(long)]
+    pub tessdata: Option<PathBuf>,
+
+    /// Recognition model for regions that don't name their own
+    #[clap(long, default_value = img::model::DEFAULT_MODEL)]
+    pub model: String,
+
+    /// Frames per second of video to analyze, 0 analyzes every frame
+    #[clap(long, default_value_t = 15.)]
+    pub sample_fps: f64,
+
+    /// Deprecated, analyzes every nth frame; use --sample-fps instead
+    #[clap(long, hide = true)]
+    pub process_frame_rate: Option<i64>,
+
+    /// Only decode keyframes, for a fast coarse scan
+    #[clap(long)]
+    pub keyframes_only: bool,
+
+    /// Convert whole frames rather than just the area the regions cover
+    #[clap(long)]
+    pub full_frame: bool,
+
+    /// Position in the video to start at, as seconds or [hh:]mm:ss[.ms]
+    #[clap(long, parse(try_from_str = parse_time))]
+    pub start: Option<f64>,
+
+    /// Position in the video to stop at, as seconds or [hh:]mm:ss[.ms]
+    #[clap(long, parse(try_from_str = parse_time))]
+    pub end: Option<f64>,
+
+    /// Region layout file (TOML or JSON) to use instead of the game's built-in regions
+    #[clap(long)]
+    pub layout: Option<PathBuf>,
+
+    /// TOML file of named image processing graphs regions can pick
+    #[clap(long)]
+    pub graphs: Option<PathBuf>,
+
+    /// Readings less confident than this, from 0 to 1, are treated as failed
+    #[clap(long, default_value_t = 0.)]
+    pub min_confidence: f64,
+
+    /// Number of recent frames each region's value is voted on over
+    #[clap(long, default_value_t = 5)]
+    pub smoothing_window: usize,
+
+    /// Share of the window that has to agree before a value is reported
+    #[clap(long, default_value_t = 0.6)]
+    pub smoothing_agreement: f64,
+
+    /// Frames in a row that have to show a new match phase before it changes
+    #[clap(long, default_value_t = 10)]
+    pub phase_frames: usize,
+
+    /// How long to wait for a late frame before output moves on without it
+    #[clap(long, default_value_t = 500)]
+    pub reorder_wait_ms: u64,
+
+    /// How many times to try reconnecting to a network stream before giving up
+    #[clap(long, default_value_t = 10)]
+    pub reconnect_attempts: u32,
+
+    /// How long a network stream can go without data before it is considered stalled
+    #[clap(long, default_value_t = 10000)]
+    pub stall_timeout_ms: u64,
+
+    /// What to do with a frame that fails to process
+    #[clap(long, arg_enum, default_value = "skip")]
+    pub on_frame_error: FrameErrorPolicy,
+
+    /// How many more times to try a failed frame with --on-frame-error retry
+    #[clap(long, default_value_t = 1)]
+    pub frame_retries: u32,
+
+    /// Write results as JSON Lines to this file, or to stdout with "-". Can be repeated.
+    #[clap(long)]
+    pub jsonl: Vec<String>,
 }
 
-pub async fn start(game: SupportedGames) {
+fn parse_time(s: &str) -> Result<f64, String> {
+    let invalid = || format!("{} is not a time, expected seconds or [hh:]mm:ss", s);
+
+    let parts: Vec<&str> = s.split(':').collect();
+    if parts.len() > 3 {
+        return Err(invalid());
+    }
+
+    let mut seconds = 0.;
+    for (i, part) in parts.iter().enumerate() {
+        let value: f64 = part.parse().map_err(|_| invalid())?;
+        // minutes and seconds after the first part have to stay below 60
+        if !value.is_finite() || value < 0. || (i > 0 && value >= 60.) {
+            return Err(invalid());
+        }
+        seconds = seconds * 60. + value;
+    }
+
+    Ok(seconds)
+}
+
+/// Reads the HUD of the game picked with `--game`.
+pub async fn run() -> error::Result<()> {
+    pretty_env_logger::init_timed();
+
+    analyze(Config::parse()).await
+}
+
+/// Reads `game`'s HUD whatever `--game` says, for callers from before the
+/// game could be picked on the command line.
+pub async fn start(game: SupportedGames) -> error::Result<()> {
     pretty_env_logger::init_timed();
 
     let mut config = Config::parse();
+    config.game = game;
+
+    analyze(config).await
+}
+
+async fn analyze(mut config: Config) -> error::Result<()> {
+    if let (Some(start), Some(end)) = (config.start, config.end) {
+        if end <= start {
+            return Err(StreamCvError::Config(
+                "the end time has to be after the start time".to_string(),
+            ));
+        }
+    }
 
     if config.num_opencv_threads == 0 {
-        let total_threads = available_parallelism().unwrap().get() as i32 / 2;
+        let total_threads = available_parallelism().map_or(2, |n| n.get()) as i32 / 2;
         config.num_opencv_threads = total_threads;
     }
 
-    if !opencv::core::use_optimized().expect("error checking for optimized code") {
+    if !opencv::core::use_optimized()? {
         debug!("changing opencv to use optimized code");
-        opencv::core::set_use_optimized(true).expect("error enabling optimized code");
+        opencv::core::set_use_optimized(true)?;
     } else {
         debug!("opencv is using optimized code")
     }
 
-    set_num_threads(config.num_opencv_threads / 2).unwrap();
+    set_num_threads(config.num_opencv_threads / 2)?;
 
-    let opencv_threads = get_num_threads().expect("error retrieving number of threads");
+    let opencv_threads = get_num_threads()?;
     debug!("opencv is using {} threads", opencv_threads);
 
-    let pipe = crate::pipeline::new();
+    if let Some(every) = config.process_frame_rate {
+        warn!("--process-frame-rate is deprecated, use --sample-fps instead");
+        if every <= 0 {
+            return Err(StreamCvError::Config(
+                "the process frame rate has to be positive".to_string(),
+            ));
+        }
+    }
+
+    let agreement = config.smoothing_agreement;
+    if agreement.is_nan() || agreement <= 0. || agreement > 1. {
+        return Err(StreamCvError::Config(
+            "the smoothing agreement has to be above 0 and at most 1".to_string(),
+        ));
+    }
+
+    if config.detect_game && (config.detect_interval.is_nan() || config.detect_interval <= 0.) {
+        return Err(StreamCvError::Config(
+            "the detection interval has to be positive".to_string(),
+        ));
+    }
+
+    let games = if config.detect_game {
+        games::registry()
+    } else {
+        vec![(config.game, games::new(config.game))]
+    };
+
+    // --layout replaces the regions of the game picked with --game
+    let layout = match &config.layout {
+        Some(path) => Some(layout::load(path)?),
+        None => None,
+    };
+    if let Some(layout) = &layout {
+        layout.check_preprocess()?;
+    }
+
+    let mut vocabularies = HashMap::new();
+    let mut graphs = HashMap::new();
+    let mut huds = Vec::new();
+    for (id, game) in games {
+        game.layout().check_preprocess()?;
+        vocabularies.extend(game.vocabularies());
+        graphs.extend(game.graphs());
+
+        let regions = match &layout {
+            Some(layout) if id == config.game => layout.regions(),
+            _ => game.regions(),
+        };
+
+        if !config.detect_game {
+            info!("reading the {} HUD", game.name());
+        }
+        huds.push((id, Arc::from(game), regions));
+    }
+    if let Some(path) = &config.graphs {
+        graphs.extend(preprocess::load(path)?);
+    }
+    if let Some(layout) = &layout {
+        vocabularies.extend(layout.vocabularies.clone());
+        graphs.extend(layout.graphs.clone());
+    }
+
+    let detector = Arc::new(detect::new(huds, &config)?);
+    let regions = detector.all_regions();
+    let parser = reading::new(vocabularies, config.min_confidence);
+    parser.check(&regions)?;
+    preprocess::check(&graphs, &regions)?;
+    state::check_phases(&regions)?;
+
+    let mut pipe = crate::pipeline::new(&config, &regions, parser, graphs)?;
     if config.show_frames {
         pipe.start_preview_thread();
     }
 
-    let game = crate::games::new(game);
-    let regions = game.regions();
+    let sinks = crate::sink::from_config(&config).map_err(StreamCvError::Sink)?;
 
+    let output = pipe.output_thread(config.show_frames, detector.clone(), sinks);
+
+    let mut workers = Vec::new();
     for i in 0..config.num_opencv_threads / 2 {
-        pipe.process_thread(i as i32, config.show_frames, regions.clone())
-            .await;
+        workers.push(pipe.process_thread(i as i32).await?);
+    }
+
+    let decoder_sender = pipe.get_decode_sender()?;
+    let gap_sender = pipe.get_gap_sender();
+    pipe.close();
+    let mut stream = VideoStream::new(config, decoder_sender, gap_sender, detector);
+
+    let started = Instant::now();
+    let result = select! {
+        result = stream.decode() => result,
+        err = pipe.fatal_error() => Err(err),
+    };
+
+    let elapsed = started.elapsed().as_secs_f64();
+    let frames_sent = stream.frames_sent();
+    info!(
+        "decoded {} frames in {:.1}s\t{:.1} fps",
+        frames_sent,
+        elapsed,
+        frames_sent as f64 / elapsed
+    );
+
+    // with the decoder's senders gone the workers drain the queue, and output
+    // writes out the reorder buffer and flushes the sinks
+    drop(stream);
+    for worker in workers {
+        if let Err(err) = worker.await {
+            error!("frame processing stopped\t{}", err);
+        }
+    }
+    if let Err(err) = output.await {
+        error!("output stopped\t{}", err);
     }
 
-    let decoder_sender = pipe.get_decode_sender();
-    let mut stream = VideoStream::new(config, decoder_sender);
-    stream.decode().await
+    if pipe.error_count() > 0 {
+        warn!("{} frame(s) failed to process", pipe.error_count());
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times_are_seconds_or_clock_times() {
+        assert_eq!(parse_time("90"), Ok(90.));
+        assert_eq!(parse_time("2.5"), Ok(2.5));
+        assert_eq!(parse_time("1:30"), Ok(90.));
+        assert_eq!(parse_time("1:02:03.5"), Ok(3723.5));
+        assert_eq!(parse_time("125:00"), Ok(7500.));
+    }
+
+    #[test]
+    fn malformed_times_are_rejected() {
+        for time in ["", "abc", "1:2:3:4", "-5", "1:", ":30"] {
+            assert!(parse_time(time).is_err(), "{} was accepted", time);
+        }
+    }
+
+    #[test]
+    fn minutes_and_seconds_stay_below_60() {
+        for time in ["1:-5", "1:75", "1:60", "1:60:00", "0:59:60"] {
+            assert!(parse_time(time).is_err(), "{} was accepted", time);
+        }
+        assert_eq!(parse_time("0:59.9"), Ok(59.9));
+    }
+
+    #[test]
+    fn non_finite_times_are_rejected() {
+        for time in ["nan", "inf", "-inf", "1:nan", "inf:30"] {
+            assert!(parse_time(time).is_err(), "{} was accepted", time);
+        }
+    }
 }
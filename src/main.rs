@@ -1,4 +1,7 @@
 #[tokio::main]
 async fn main() {
-    stream_cv::start(stream_cv::games::SupportedGames::Apex).await;
+    if let Err(err) = stream_cv::run().await {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    }
 }
@@ -1,52 +1,132 @@
 use chrono::Utc;
 use flume::{bounded, Receiver, Sender};
 use futures::StreamExt;
-use opencv::{
-    dnn::{self, TextRecognitionModel},
-    highgui::{imshow, poll_key},
+use opencv::highgui::{imshow, poll_key};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
 };
-use std::{sync::Arc, thread};
-use tokio::{select, sync::Mutex};
+use tokio::{select, task::JoinHandle, time::sleep};
 
 use crate::{
-    games::apex::Weapon,
-    img::{self, frame::Frame},
-    roi::RegionOfInterestList,
+    detect::{FrameKind, GameDetector, Switch},
+    error::{FrameErrorPolicy, Result, StreamCvError},
+    img::{
+        frame::Frame,
+        model::{self, ModelRegistry},
+        preprocess::Graphs,
+    },
+    reading::Parser,
+    reorder,
+    roi::{self, RegionOfInterestList},
+    sink::{FrameRecord, GapRecord, PauseRecord, PhaseRecord, ResultSink},
     state::{self, GameState},
+    Config,
 };
 
+/// What a worker hands to the output thread.
+enum Processed {
+    Frame(Frame),
+    /// The frame with this sequence number failed and was dropped.
+    Skipped(i64),
+}
+
 #[allow(dead_code)]
 #[derive(Clone)]
 pub struct Pipeline {
     decode_receiver: Receiver<Frame>,
-    decode_sender: Sender<Frame>,
+    /// Taken by `close`, so workers see the end of the stream once the
+    /// decoder's senders are gone too.
+    decode_sender: Option<Sender<Frame>>,
+    processed_receiver: Receiver<Processed>,
+    processed_sender: Option<Sender<Processed>>,
     preview_receiver: Receiver<Frame>,
     preview_sender: Sender<Frame>,
-    recognizer: Arc<Mutex<dnn::TextRecognitionModel>>,
+    gap_receiver: Receiver<GapRecord>,
+    gap_sender: Sender<GapRecord>,
+    fatal_receiver: Receiver<StreamCvError>,
+    fatal_sender: Sender<StreamCvError>,
+    models: ModelRegistry,
+    parser: Arc<Parser>,
+    graphs: Arc<Graphs>,
     state: GameState,
+    reorder_wait: Duration,
+    error_policy: FrameErrorPolicy,
+    frame_retries: u32,
+    errors: Arc<AtomicU64>,
 }
 
-pub fn new() -> Pipeline {
+pub fn new(
+    config: &Config,
+    regions: &RegionOfInterestList,
+    parser: Parser,
+    graphs: Graphs,
+) -> Result<Pipeline> {
     let (decode_sender, decode_receiver) = bounded::<Frame>(60);
+    let (processed_sender, processed_receiver) = bounded::<Processed>(60);
     let (preview_sender, preview_receiver) = bounded::<Frame>(60);
+    let (gap_sender, gap_receiver) = bounded::<GapRecord>(60);
+    let (fatal_sender, fatal_receiver) = bounded::<StreamCvError>(1);
 
-    let base_recognizer = img::make_text_recognizer();
-
-    let recognizer = Arc::new(Mutex::new(base_recognizer));
+    let models = model::registry(config, regions, parser.vocabularies())?;
 
-    Pipeline {
+    Ok(Pipeline {
         decode_receiver,
-        decode_sender,
+        decode_sender: Some(decode_sender),
+        processed_receiver,
+        processed_sender: Some(processed_sender),
         preview_receiver,
         preview_sender,
-        recognizer,
-        state: state::new(),
-    }
+        gap_receiver,
+        gap_sender,
+        fatal_receiver,
+        fatal_sender,
+        models,
+        parser: Arc::new(parser),
+        graphs: Arc::new(graphs),
+        state: state::new(
+            config.smoothing_window,
+            config.smoothing_agreement,
+            config.phase_frames,
+        ),
+        reorder_wait: Duration::from_millis(config.reorder_wait_ms),
+        error_policy: config.on_frame_error,
+        frame_retries: config.frame_retries,
+        errors: Arc::new(AtomicU64::new(0)),
+    })
 }
 
 impl Pipeline {
-    pub fn get_decode_sender(&self) -> Sender<Frame> {
-        self.decode_sender.clone()
+    pub fn get_decode_sender(&self) -> Result<Sender<Frame>> {
+        self.decode_sender
+            .clone()
+            .ok_or(StreamCvError::PipelineClosed)
+    }
+    pub fn get_gap_sender(&self) -> Sender<GapRecord> {
+        self.gap_sender.clone()
+    }
+    /// Drops the pipeline's own senders once every worker is running. The
+    /// workers then finish when the decoder's senders are dropped, after
+    /// draining what was queued.
+    pub fn close(&mut self) {
+        self.decode_sender = None;
+        self.processed_sender = None;
+    }
+    /// Number of frames that failed to process so far.
+    pub fn error_count(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+    /// Resolves once a frame fails and the error policy is to abort.
+    pub async fn fatal_error(&self) -> StreamCvError {
+        self.fatal_receiver
+            .recv_async()
+            .await
+            .unwrap_or(StreamCvError::PipelineClosed)
     }
     pub fn start_preview_thread(&self) {
         let p = self.preview_receiver.clone();
@@ -54,51 +134,292 @@ impl Pipeline {
             spawn_preview_thread(p);
         });
     }
-    pub async fn process_thread(
+    pub async fn process_thread(&self, thread_num: i32) -> Result<JoinHandle<()>> {
+        let processed_send = self
+            .processed_sender
+            .clone()
+            .ok_or(StreamCvError::PipelineClosed)?;
+        let decode_receiver = self.decode_receiver.clone();
+        let models = self.models.clone();
+        let parser = self.parser.clone();
+        let graphs = self.graphs.clone();
+        let state = self.state.clone();
+        let fatal_send = self.fatal_sender.clone();
+        let errors = self.errors.clone();
+        let policy = self.error_policy;
+        let retries = match policy {
+            FrameErrorPolicy::Retry => self.frame_retries,
+            _ => 0,
+        };
+
+        Ok(tokio::spawn(async move {
+            let mut decode_stream = decode_receiver.stream();
+
+            // ends once the decoder is done and the queue is drained
+            while let Some(mut f) = decode_stream.next().await {
+                debug!(
+                    "thread {}\tframe {}\tdecoded\t\tqueue len{}",
+                    thread_num,
+                    f.num,
+                    decode_stream.len()
+                );
+
+                // only the regions that mean something in the current phase are
+                // read. The output thread changes the phase as it writes frames
+                // out, so frames already queued or in flight when it changes are
+                // still read with the last phase's regions. That's accepted: a
+                // change takes --phase-frames frames to confirm anyway, and the
+                // markers that lead out of a phase are read in it.
+                if let (FrameKind::Hud(_), Some(phase)) = (f.kind, state.phase().await) {
+                    f.results = phase.regions(&f.results);
+                }
+
+                let (num, seq) = (f.num, f.seq);
+                let processed =
+                    match process_with_retries(f, retries, &models, &parser, &graphs).await {
+                        Ok(f) => {
+                            let process_time = Utc::now() - f.start_date;
+                            debug!(
+                                "thread {}\tframe {}\tprocessed\tduration {}ms",
+                                thread_num,
+                                f.num,
+                                process_time.num_milliseconds()
+                            );
+
+                            Processed::Frame(f)
+                        }
+                        Err(err) => {
+                            errors.fetch_add(1, Ordering::Relaxed);
+
+                            if policy == FrameErrorPolicy::Abort {
+                                let _ = fatal_send.try_send(StreamCvError::Aborted {
+                                    frame: num,
+                                    source: Box::new(err),
+                                });
+                                break;
+                            }
+
+                            error!(
+                                "thread {}\tframe {}\tskipping frame\t{}",
+                                thread_num, num, err
+                            );
+                            Processed::Skipped(seq)
+                        }
+                    };
+
+                if processed_send.send_async(processed).await.is_err() {
+                    break;
+                }
+            }
+        }))
+    }
+
+    /// Puts processed frames back in order and hands them to the game on
+    /// screen, or to `detector` for probe frames.
+    pub fn output_thread(
         &self,
-        thread_num: i32,
         show_frames: bool,
-        regions: RegionOfInterestList,
-    ) {
+        detector: Arc<GameDetector>,
+        mut sinks: Vec<Box<dyn ResultSink>>,
+    ) -> JoinHandle<()> {
+        let processed_receiver = self.processed_receiver.clone();
+        let gap_receiver = self.gap_receiver.clone();
         let preview_send = self.preview_sender.clone();
-        let decode_receiver = self.decode_receiver.clone();
-        let recognizer = self.recognizer.clone();
+        let state = self.state.clone();
+        let mut reorder = reorder::new(60, self.reorder_wait);
+        let mut gaps: VecDeque<GapRecord> = VecDeque::new();
 
         tokio::spawn(async move {
-            let mut decode_stream = decode_receiver.stream();
+            let mut finished = false;
+            // written once reading resumes, or at the end if it never does
+            let mut pause: Option<PauseRecord> = None;
 
             loop {
-                select! {
-                    frame = decode_stream.next() => {
-                        if let Some(mut f) = frame {
-                           debug!("thread {}\tframe {}\tdecoded\t\tqueue len{}", thread_num, f.num, decode_stream.len());
+                let deadline = reorder.time_until_deadline();
 
-                            process_frame(&mut f, regions.clone(), &recognizer).await;
-                            let process_time = Utc::now() - f.start_date;
+                let ready = select! {
+                    received = processed_receiver.recv_async() => match received {
+                        Ok(Processed::Frame(frame)) => {
+                            reorder.push(frame);
+                            reorder.pop_ready()
+                        }
+                        Ok(Processed::Skipped(seq)) => {
+                            reorder.skip(seq);
+                            reorder.pop_ready()
+                        }
+                        Err(_) => {
+                            finished = true;
+                            gaps.extend(gap_receiver.try_iter());
+                            reorder.flush()
+                        }
+                    },
+                    Ok(gap) = gap_receiver.recv_async() => {
+                        gaps.push_back(gap);
+                        Vec::new()
+                    }
+                    _ = sleep(deadline.unwrap_or_default()), if deadline.is_some() => reorder.pop_ready(),
+                };
 
-                            debug!("thread {}\tframe {}\tprocessed\tduration {}ms", thread_num, f.num, process_time.num_milliseconds());
+                for mut f in ready {
+                    // gaps go out between the last frame before and the first frame after them
+                    for gap in gaps_before(&mut gaps, f.seq) {
+                        write_gap(&mut sinks, &gap);
+                    }
 
-                            let w1 = f.results.get_value("weapon_1_name".to_string()).unwrap();
-                            let w2 = f.results.get_value("weapon_2_name".to_string()).unwrap();
-                            let sim1 = Weapon::match_string(w1.result.as_ref().unwrap().to_string());
-                            let sim2 = Weapon::match_string(w2.result.as_ref().unwrap().to_string());
+                    let game = match f.kind {
+                        FrameKind::Probe => {
+                            if let Some(Switch { from, to }) = detector.classify(&f) {
+                                match to.and_then(|id| detector.game(id)) {
+                                    Some(game) => info!(
+                                        "frame {}\t{:.3}s\t{} is on screen",
+                                        f.num,
+                                        f.timestamp.unwrap_or_default(),
+                                        game.name()
+                                    ),
+                                    None => info!(
+                                        "frame {}\t{:.3}s\tno known HUD on screen, pausing",
+                                        f.num,
+                                        f.timestamp.unwrap_or_default()
+                                    ),
+                                }
+                                // reads of the last game mean nothing to the next
+                                if from.is_some() {
+                                    state.reset().await;
+                                }
 
-                            println!("::::::::::::::::::::::::::::::::: sim1: {:?} sim2: {:?}", sim1, sim2);
+                                if to.is_none() && from.is_some() {
+                                    pause = Some(PauseRecord {
+                                        paused_at: f.timestamp,
+                                        resumed_at: None,
+                                        before: from,
+                                        after: None,
+                                    });
+                                } else if let Some(mut paused) = pause.take() {
+                                    paused.resumed_at = f.timestamp;
+                                    paused.after = to;
+                                    write_pause(&mut sinks, &paused);
+                                }
+                            }
+                            continue;
+                        }
+                        FrameKind::Hud(id) => match detector.game(id) {
+                            Some(game) => game.clone(),
+                            None => continue,
+                        },
+                    };
+
+                    if let Some((from, to)) = state.observe_phase(game.match_phase(&f)).await {
+                        write_phase(
+                            &mut sinks,
+                            &PhaseRecord {
+                                num: f.num,
+                                timestamp: f.timestamp,
+                                from,
+                                to,
+                            },
+                        );
+                    }
 
-                            info!("frame {}\toutput", f.num);
+                    f.results = state.smooth(&f.results).await;
+                    let data = game.extract_data(&f);
 
-                            if show_frames {
-                                preview_send.send(f.clone()).unwrap();
-                            }
+                    info!(
+                        "frame {}\t{:.3}s\toutput\t{}",
+                        f.num,
+                        f.timestamp.unwrap_or_default(),
+                        game.output(&data)
+                    );
+
+                    let record = FrameRecord {
+                        num: f.num,
+                        pts: f.pts,
+                        timestamp: f.timestamp,
+                        received_at: f.start_date,
+                        results: f.results.clone(),
+                        data,
+                        phase: state.phase().await,
+                    };
+                    for sink in sinks.iter_mut() {
+                        if let Err(err) = sink.write_frame(&record) {
+                            error!("frame {}\tunable to write to sink\t{}", f.num, err);
                         }
                     }
+
+                    if show_frames && preview_send.send_async(f).await.is_err() {
+                        warn!("preview window closed");
+                    }
+                }
+
+                let released = if finished {
+                    i64::MAX
+                } else {
+                    reorder.next_seq()
+                };
+                for gap in gaps_before(&mut gaps, released) {
+                    write_gap(&mut sinks, &gap);
+                }
+
+                // every worker is done and the reorder buffer has been flushed
+                if finished {
+                    if let Some(paused) = pause.take() {
+                        write_pause(&mut sinks, &paused);
+                    }
+                    for sink in sinks.iter_mut() {
+                        if let Err(err) = sink.flush() {
+                            error!("unable to flush sink\t{}", err);
+                        }
+                    }
+                    break;
                 }
             }
-        });
+        })
+    }
+}
+
+/// Removes the gaps that go out before the frame at sequence number `seq`.
+fn gaps_before(gaps: &mut VecDeque<GapRecord>, seq: i64) -> Vec<GapRecord> {
+    let due = gaps.iter().take_while(|gap| gap.seq <= seq).count();
+    gaps.drain(..due).collect()
+}
+
+fn write_gap(sinks: &mut [Box<dyn ResultSink>], gap: &GapRecord) {
+    warn!(
+        "stream gap after {:.3}s\t{}",
+        gap.last_timestamp.unwrap_or_default(),
+        gap.reason
+    );
+
+    for sink in sinks.iter_mut() {
+        if let Err(err) = sink.write_gap(gap) {
+            error!("unable to write gap to sink\t{}", err);
+        }
     }
 }
 
-fn show_frame(mut frame: Frame) {
+fn write_pause(sinks: &mut [Box<dyn ResultSink>], pause: &PauseRecord) {
+    for sink in sinks.iter_mut() {
+        if let Err(err) = sink.write_pause(pause) {
+            error!("unable to write pause to sink\t{}", err);
+        }
+    }
+}
+
+fn write_phase(sinks: &mut [Box<dyn ResultSink>], phase: &PhaseRecord) {
+    info!(
+        "frame {}\t{:.3}s\tmatch phase is now {}",
+        phase.num,
+        phase.timestamp.unwrap_or_default(),
+        phase.to
+    );
+
+    for sink in sinks.iter_mut() {
+        if let Err(err) = sink.write_phase(phase) {
+            error!("unable to write phase change to sink\t{}", err);
+        }
+    }
+}
+
+fn show_frame(mut frame: Frame) -> Result<()> {
     let time_to_preview = Utc::now() - frame.start_date;
     debug!(
         "frame {}\tshowing frame\t{}ms",
@@ -106,40 +427,139 @@ fn show_frame(mut frame: Frame) {
         time_to_preview.num_milliseconds()
     );
 
-    frame.highlight_regions();
+    frame.highlight_regions()?;
+
+    imshow("frames", &frame.processed_mat)?;
+    poll_key()?;
 
-    imshow("frames", &frame.processed_mat).unwrap();
-    poll_key().unwrap();
+    Ok(())
 }
 
 fn spawn_preview_thread(recv: Receiver<Frame>) {
     while let Ok(frame) = recv.recv() {
-        show_frame(frame);
+        let num = frame.num;
+        if let Err(err) = show_frame(frame) {
+            error!("frame {}\tunable to show frame\t{}", num, err);
+        }
     }
 }
 
+/// Processes a frame, trying again on a fresh copy up to `retries` times if
+/// it fails.
+async fn process_with_retries(
+    frame: Frame,
+    retries: u32,
+    models: &ModelRegistry,
+    parser: &Parser,
+    graphs: &Graphs,
+) -> Result<Frame> {
+    for attempt in 1..=retries {
+        let mut copy = frame.clone();
+        match process_frame(&mut copy, models, parser, graphs).await {
+            Ok(_) => return Ok(copy),
+            Err(err) => warn!(
+                "frame {}\tattempt {} of {} failed\t{}",
+                frame.num,
+                attempt,
+                retries + 1,
+                err
+            ),
+        }
+    }
+
+    let mut frame = frame;
+    process_frame(&mut frame, models, parser, graphs).await?;
+
+    Ok(frame)
+}
+
 async fn process_frame(
     frame: &mut Frame,
-    regions: RegionOfInterestList,
-    recognizer: &Arc<Mutex<TextRecognitionModel>>,
-) {
-    frame
-        .bilateral_filter(9, 75., 75.)
-        .await
-        .adjust_contrast(1.55)
-        .await
-        .adjust_brightness(-10.)
-        .await;
-
-    if !regions.is_empty() {
-        process_regions(frame, regions, recognizer).await;
+    models: &ModelRegistry,
+    parser: &Parser,
+    graphs: &Graphs,
+) -> Result<()> {
+    if !frame.results.is_empty() {
+        process_regions(frame, models, parser, graphs).await?;
     }
+
+    Ok(())
 }
 
+/// Reads the regions, batching together those that share an engine, and
+/// parses what was read.
 async fn process_regions(
     frame: &mut Frame,
-    regions: RegionOfInterestList,
-    recognizer: &Arc<Mutex<TextRecognitionModel>>,
-) {
-    frame.list_text_recognition(regions, recognizer).await;
+    models: &ModelRegistry,
+    parser: &Parser,
+    graphs: &Graphs,
+) -> Result<()> {
+    let mut by_model: BTreeMap<String, RegionOfInterestList> = BTreeMap::new();
+    for (_, region) in frame.results.iter() {
+        by_model
+            .entry(models.engine_name(&region))
+            .or_insert_with(roi::new_region_list)
+            .add_region(region);
+    }
+
+    for (name, regions) in by_model {
+        frame
+            .list_text_recognition(regions, models.get(&name).as_ref(), graphs)
+            .await?;
+    }
+
+    parser.parse_all(&mut frame.results);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gap(seq: i64) -> GapRecord {
+        GapRecord {
+            seq,
+            reason: "stream stalled".to_string(),
+            last_timestamp: None,
+            started_at: Utc::now(),
+            resumed_at: None,
+            reconnect_attempts: 0,
+        }
+    }
+
+    #[test]
+    fn gaps_go_out_between_the_frames_around_them() {
+        let mut gaps = VecDeque::from([gap(2), gap(4)]);
+        let mut written = Vec::new();
+
+        for seq in 0..6 {
+            for gap in gaps_before(&mut gaps, seq) {
+                written.push(format!("gap {}", gap.seq));
+            }
+            written.push(format!("frame {}", seq));
+        }
+
+        assert_eq!(
+            written,
+            vec![
+                "frame 0", "frame 1", "gap 2", "frame 2", "frame 3", "gap 4", "frame 4", "frame 5",
+            ]
+        );
+        assert!(gaps.is_empty());
+    }
+
+    #[test]
+    fn gaps_wait_for_the_frame_after_them() {
+        let mut gaps = VecDeque::from([gap(3), gap(7)]);
+
+        assert!(gaps_before(&mut gaps, 2).is_empty());
+        assert_eq!(gaps.len(), 2);
+
+        let written: Vec<i64> = gaps_before(&mut gaps, i64::MAX)
+            .iter()
+            .map(|gap| gap.seq)
+            .collect();
+        assert_eq!(written, vec![3, 7]);
+    }
 }
@@ -0,0 +1,380 @@
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::{
+    error::{Result, StreamCvError},
+    roi::{RegionOfInterest, RegionOfInterestList, RegionOfInterestType},
+};
+
+/// Named lists of the words an enum region can read.
+pub type Vocabularies = HashMap<String, Vec<String>>;
+
+/// Similarity below which an enum read isn't taken to be any word.
+const DEFAULT_MIN_SIMILARITY: f64 = 0.75;
+
+/// Confidence below which enum readings fail unless the region sets its own
+/// `min_confidence`. Lexicon decoding reads some word from any crop, even an
+/// empty one, so a low score is the only sign nothing was there.
+const DEFAULT_MIN_ENUM_CONFIDENCE: f64 = 0.5;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Value {
+    Integer(i64),
+    Text(String),
+}
+
+/// A word a region might have shown, with how likely it is from 0 to 1.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Candidate {
+    pub text: String,
+    pub confidence: f64,
+}
+
+/// What a region's text was parsed into, according to its type.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Reading {
+    /// `confidence` runs from 0 to 1, lower the more the text had to be
+    /// corrected to fit the region's type.
+    Parsed { value: Value, confidence: f64 },
+    /// Something was read, but it isn't a value the region can show.
+    Failed { text: String, reason: String },
+}
+
+impl Reading {
+    pub fn value(&self) -> Option<&Value> {
+        match self {
+            Reading::Parsed { value, .. } => Some(value),
+            Reading::Failed { .. } => None,
+        }
+    }
+
+    pub fn integer(&self) -> Option<i64> {
+        match self.value() {
+            Some(Value::Integer(value)) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn text(&self) -> Option<&str> {
+        match self.value() {
+            Some(Value::Text(text)) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn confidence(&self) -> f64 {
+        match self {
+            Reading::Parsed { confidence, .. } => *confidence,
+            Reading::Failed { .. } => 0.,
+        }
+    }
+}
+
+/// Characters the recognizer tends to confuse with a digit.
+fn as_digit(c: char) -> Option<char> {
+    match c {
+        '0'..='9' => Some(c),
+        'O' | 'o' | 'D' | 'Q' | 'U' => Some('0'),
+        'l' | 'I' | 'i' | 'j' | 'J' | '|' | '!' => Some('1'),
+        'Z' | 'z' => Some('2'),
+        'A' => Some('4'),
+        'S' | 's' => Some('5'),
+        'G' | 'b' => Some('6'),
+        'T' => Some('7'),
+        'B' => Some('8'),
+        'g' | 'q' => Some('9'),
+        _ => None,
+    }
+}
+
+/// Maps look-alike characters to digits, returning the digits and the share
+/// of characters that didn't need correcting.
+fn correct_digits(text: &str) -> std::result::Result<(String, f64), String> {
+    let mut digits = String::new();
+    let mut corrected = 0;
+
+    for c in text.chars().filter(|c| !c.is_whitespace()) {
+        match as_digit(c) {
+            Some(digit) => {
+                if digit != c {
+                    corrected += 1;
+                }
+                digits.push(digit);
+            }
+            None => return Err(format!("{} isn't a digit", c)),
+        }
+    }
+
+    let confidence = 1. - corrected as f64 / digits.len() as f64;
+
+    Ok((digits, confidence))
+}
+
+fn parse_integer(region: &RegionOfInterest, text: &str) -> std::result::Result<Reading, String> {
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+
+    let (digits, confidence) = correct_digits(text)?;
+    let mut value: i64 = digits
+        .parse()
+        .map_err(|_| format!("{} isn't a whole number", digits))?;
+    if negative {
+        value = -value;
+    }
+
+    let bound = |name: &str| {
+        region
+            .options
+            .get(name)
+            .and_then(|bound| bound.parse::<i64>().ok())
+    };
+    if bound("min").is_some_and(|min| value < min) || bound("max").is_some_and(|max| value > max) {
+        return Err(format!("{} is out of range", value));
+    }
+
+    Ok(Reading::Parsed {
+        value: Value::Integer(value),
+        confidence,
+    })
+}
+
+fn parse_word(
+    region: &RegionOfInterest,
+    vocabulary: &str,
+    text: &str,
+    vocabularies: &Vocabularies,
+) -> std::result::Result<Reading, String> {
+    let words = vocabularies
+        .get(vocabulary)
+        .ok_or_else(|| format!("vocabulary {} isn't defined", vocabulary))?;
+
+    let min_similarity = region
+        .options
+        .get("min_similarity")
+        .and_then(|min| min.parse().ok())
+        .unwrap_or(DEFAULT_MIN_SIMILARITY);
+
+    let text = text.to_lowercase();
+    let (word, similarity) = words
+        .iter()
+        .map(|word| (word, strsim::jaro_winkler(&text, &word.to_lowercase())))
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .ok_or_else(|| format!("vocabulary {} is empty", vocabulary))?;
+
+    if similarity < min_similarity {
+        return Err(format!(
+            "closest word {} is only {:.2} similar",
+            word, similarity
+        ));
+    }
+
+    Ok(Reading::Parsed {
+        value: Value::Text(word.clone()),
+        confidence: similarity,
+    })
+}
+
+/// Turns what regions read into typed values.
+#[derive(Debug, Clone)]
+pub struct Parser {
+    vocabularies: Vocabularies,
+    min_confidence: f64,
+}
+
+/// Creates a parser that fails readings less confident than
+/// `min_confidence`, unless a region sets its own `min_confidence` option.
+/// Enum readings fail below 0.5 even if `min_confidence` is lower.
+pub fn new(vocabularies: Vocabularies, min_confidence: f64) -> Parser {
+    Parser {
+        vocabularies,
+        min_confidence,
+    }
+}
+
+impl Parser {
+    /// Parses what was read in `region` according to its type. Returns
+    /// `None` if nothing was read.
+    ///
+    /// The reading's confidence combines the recognizer's confidence with
+    /// how much the text had to be corrected, or for enum regions how
+    /// similar it is to the matched word.
+    pub fn parse(&self, region: &RegionOfInterest) -> Option<Reading> {
+        let text = region.result.as_deref()?.trim();
+        if text.is_empty() {
+            return None;
+        }
+
+        let parsed = match &region.roi_type {
+            RegionOfInterestType::FreeText => Ok(Reading::Parsed {
+                value: Value::Text(text.to_string()),
+                confidence: 1.,
+            }),
+            RegionOfInterestType::Integer => parse_integer(region, text),
+            RegionOfInterestType::Digits => {
+                correct_digits(text).map(|(digits, confidence)| Reading::Parsed {
+                    value: Value::Text(digits),
+                    confidence,
+                })
+            }
+            RegionOfInterestType::Enum(vocabulary) => {
+                parse_word(region, vocabulary, text, &self.vocabularies)
+            }
+        };
+
+        let min_confidence = region
+            .options
+            .get("min_confidence")
+            .and_then(|min| min.parse().ok())
+            .unwrap_or_else(|| match region.roi_type {
+                RegionOfInterestType::Enum(_) => {
+                    self.min_confidence.max(DEFAULT_MIN_ENUM_CONFIDENCE)
+                }
+                _ => self.min_confidence,
+            });
+
+        let reading = parsed.and_then(|reading| match reading {
+            Reading::Parsed { value, confidence } => {
+                let confidence = confidence * region.confidence.unwrap_or(1.);
+                if confidence < min_confidence {
+                    Err(format!(
+                        "confidence {:.2} is below {:.2}",
+                        confidence, min_confidence
+                    ))
+                } else {
+                    Ok(Reading::Parsed { value, confidence })
+                }
+            }
+            failed => Ok(failed),
+        });
+
+        Some(reading.unwrap_or_else(|reason| Reading::Failed {
+            text: text.to_string(),
+            reason,
+        }))
+    }
+
+    /// Parses every region's result, replacing the text of those that
+    /// parsed with its corrected form.
+    pub fn parse_all(&self, results: &mut RegionOfInterestList) {
+        for (_, mut region) in results.iter() {
+            region.reading = self.parse(&region);
+
+            match region.reading.as_ref().and_then(Reading::value) {
+                Some(Value::Integer(value)) => region.result = Some(value.to_string()),
+                Some(Value::Text(text)) => region.result = Some(text.clone()),
+                None => {}
+            }
+
+            results.add_region(region);
+        }
+    }
+
+    pub fn vocabularies(&self) -> &Vocabularies {
+        &self.vocabularies
+    }
+
+    /// Fails if a region reads from a vocabulary that doesn't exist.
+    pub fn check(&self, regions: &RegionOfInterestList) -> Result<()> {
+        for (name, region) in regions.iter() {
+            if let RegionOfInterestType::Enum(vocabulary) = &region.roi_type {
+                if !self.vocabularies.contains_key(vocabulary) {
+                    return Err(StreamCvError::Config(format!(
+                        "region {} reads from vocabulary {}, which isn't defined",
+                        name, vocabulary
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roi::{self, StreamResolution};
+
+    fn integer_region(min: Option<&str>, max: Option<&str>) -> RegionOfInterest {
+        let mut region =
+            roi::new_region("ammo".to_string(), 0, 0, 10, 10, StreamResolution::HD1080P);
+        region.roi_type = RegionOfInterestType::Integer;
+        if let Some(min) = min {
+            region.options.insert("min".to_string(), min.to_string());
+        }
+        if let Some(max) = max {
+            region.options.insert("max".to_string(), max.to_string());
+        }
+
+        region
+    }
+
+    fn integer(reading: Reading) -> (i64, f64) {
+        match reading {
+            Reading::Parsed {
+                value: Value::Integer(value),
+                confidence,
+            } => (value, confidence),
+            other => panic!("expected an integer, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn digits_are_read_as_is() {
+        assert_eq!(correct_digits("0123"), Ok(("0123".to_string(), 1.)));
+        assert_eq!(correct_digits(" 4 2 "), Ok(("42".to_string(), 1.)));
+    }
+
+    #[test]
+    fn look_alikes_are_corrected_at_a_cost() {
+        assert_eq!(correct_digits("1O"), Ok(("10".to_string(), 0.5)));
+        assert_eq!(correct_digits("SlB"), Ok(("518".to_string(), 0.)));
+    }
+
+    #[test]
+    fn other_characters_fail() {
+        assert!(correct_digits("12x").is_err());
+        assert!(correct_digits("1.5").is_err());
+    }
+
+    #[test]
+    fn integers_parse_with_their_sign() {
+        let region = integer_region(None, None);
+
+        assert_eq!(integer(parse_integer(&region, "42").unwrap()), (42, 1.));
+        assert_eq!(integer(parse_integer(&region, "-7").unwrap()), (-7, 1.));
+        assert_eq!(integer(parse_integer(&region, "l2").unwrap()), (12, 0.5));
+        assert!(parse_integer(&region, "-").is_err());
+        assert!(parse_integer(&region, "4-2").is_err());
+    }
+
+    #[test]
+    fn integers_stay_within_their_bounds() {
+        let region = integer_region(Some("0"), Some("359"));
+
+        assert_eq!(integer(parse_integer(&region, "0").unwrap()), (0, 1.));
+        assert_eq!(integer(parse_integer(&region, "359").unwrap()), (359, 1.));
+        assert!(parse_integer(&region, "360").is_err());
+        assert!(parse_integer(&region, "-1").is_err());
+    }
+
+    #[test]
+    fn unconfident_readings_fail() {
+        let parser = new(Vocabularies::new(), 0.3);
+        let mut region = integer_region(None, None);
+        region.set_result("l2".to_string());
+        region.confidence = Some(0.5);
+
+        assert!(matches!(
+            parser.parse(&region),
+            Some(Reading::Failed { text, .. }) if text == "l2"
+        ));
+
+        region.set_result("12".to_string());
+        assert_eq!(integer(parser.parse(&region).unwrap()), (12, 0.5));
+    }
+}
@@ -0,0 +1,252 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::{Duration, Instant},
+};
+
+use crate::img::frame::Frame;
+
+/// Puts frames finished by parallel workers back into the order they were
+/// decoded in.
+///
+/// A frame is held back until every frame sent before it has been released.
+/// If the missing frame doesn't show up within `max_wait`, or `capacity`
+/// frames pile up behind it, it is given up on and the buffer moves past it.
+pub struct ReorderBuffer {
+    /// Frames waiting on earlier ones, by sequence number.
+    pending: BTreeMap<i64, Frame>,
+    /// Frames that failed to process and will never arrive.
+    skipped: BTreeSet<i64>,
+    next_seq: i64,
+    capacity: usize,
+    max_wait: Duration,
+    waiting_since: Option<Instant>,
+}
+
+pub fn new(capacity: usize, max_wait: Duration) -> ReorderBuffer {
+    ReorderBuffer {
+        pending: BTreeMap::new(),
+        skipped: BTreeSet::new(),
+        next_seq: 0,
+        capacity: capacity.max(1),
+        max_wait,
+        waiting_since: None,
+    }
+}
+
+impl ReorderBuffer {
+    pub fn push(&mut self, frame: Frame) {
+        if frame.seq < self.next_seq {
+            warn!(
+                "frame {}\tarrived after later frames were released, dropping it",
+                frame.num
+            );
+            return;
+        }
+
+        self.pending.insert(frame.seq, frame);
+    }
+
+    /// Marks a frame as never arriving, so the buffer doesn't wait on it.
+    pub fn skip(&mut self, seq: i64) {
+        if seq >= self.next_seq {
+            self.skipped.insert(seq);
+        }
+    }
+
+    fn advance_past_skipped(&mut self) {
+        while self.skipped.remove(&self.next_seq) {
+            self.next_seq += 1;
+            self.waiting_since = None;
+        }
+    }
+
+    /// Removes the frames that can be released, in order.
+    pub fn pop_ready(&mut self) -> Vec<Frame> {
+        let mut ready = Vec::new();
+
+        self.advance_past_skipped();
+        while let Some((&seq, head)) = self.pending.first_key_value() {
+            if seq != self.next_seq {
+                let waited_too_long = self
+                    .waiting_since
+                    .get_or_insert_with(Instant::now)
+                    .elapsed()
+                    >= self.max_wait;
+
+                if !waited_too_long && self.pending.len() < self.capacity {
+                    break;
+                }
+
+                warn!(
+                    "gave up waiting on {} frame(s) before frame {}",
+                    seq - self.next_seq,
+                    head.num
+                );
+                self.next_seq = seq;
+                self.skipped = self.skipped.split_off(&seq);
+            }
+
+            let frame = self.pending.remove(&seq).unwrap();
+            self.next_seq = frame.seq + 1;
+            self.waiting_since = None;
+            ready.push(frame);
+            self.advance_past_skipped();
+        }
+
+        ready
+    }
+
+    /// Sequence number of the next frame to be released.
+    pub fn next_seq(&self) -> i64 {
+        self.next_seq
+    }
+
+    /// How long until the buffer gives up on the frame it is waiting for.
+    pub fn time_until_deadline(&self) -> Option<Duration> {
+        self.waiting_since
+            .map(|since| self.max_wait.saturating_sub(since.elapsed()))
+    }
+
+    /// Removes every remaining frame in order, regardless of gaps.
+    pub fn flush(&mut self) -> Vec<Frame> {
+        let frames: Vec<Frame> = std::mem::take(&mut self.pending).into_values().collect();
+        if let Some(last) = frames.last() {
+            self.next_seq = last.seq + 1;
+        }
+        self.skipped.clear();
+        self.waiting_since = None;
+
+        frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use opencv::core::{Mat, Point, UMat, UMatUsageFlags};
+
+    use super::*;
+    use crate::{detect::FrameKind, roi};
+
+    const LONG_WAIT: Duration = Duration::from_secs(60);
+
+    fn frame(seq: i64) -> Frame {
+        numbered(seq, seq)
+    }
+
+    /// A frame whose number doesn't follow its sequence number, as when
+    /// numbers are derived from timestamps.
+    fn numbered(num: i64, seq: i64) -> Frame {
+        Frame {
+            mat: Mat::default(),
+            processed_mat: UMat::new(UMatUsageFlags::USAGE_DEFAULT),
+            offset: Point::default(),
+            kind: FrameKind::Probe,
+            num,
+            seq,
+            pts: None,
+            timestamp: None,
+            text: String::new(),
+            start_date: Utc::now(),
+            end_date: None,
+            results: roi::new_region_list(),
+        }
+    }
+
+    fn seqs(frames: Vec<Frame>) -> Vec<i64> {
+        frames.into_iter().map(|frame| frame.seq).collect()
+    }
+
+    #[test]
+    fn releases_frames_in_order() {
+        let mut buffer = new(8, LONG_WAIT);
+        buffer.push(frame(2));
+        buffer.push(frame(0));
+        buffer.push(frame(1));
+
+        assert_eq!(seqs(buffer.pop_ready()), vec![0, 1, 2]);
+        assert_eq!(buffer.next_seq(), 3);
+    }
+
+    #[test]
+    fn order_follows_the_sequence_not_the_frame_number() {
+        let mut buffer = new(8, LONG_WAIT);
+        buffer.push(numbered(90, 1));
+        buffer.push(numbered(120, 0));
+        buffer.push(numbered(60, 2));
+
+        let released = buffer.pop_ready();
+        assert_eq!(
+            released.iter().map(|frame| frame.num).collect::<Vec<_>>(),
+            vec![120, 90, 60]
+        );
+        assert_eq!(seqs(released), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn holds_frames_behind_a_missing_one() {
+        let mut buffer = new(8, LONG_WAIT);
+        buffer.push(frame(1));
+
+        assert!(buffer.pop_ready().is_empty());
+        assert!(buffer.time_until_deadline().is_some());
+
+        buffer.push(frame(0));
+        assert_eq!(seqs(buffer.pop_ready()), vec![0, 1]);
+        assert!(buffer.time_until_deadline().is_none());
+    }
+
+    #[test]
+    fn skipped_frames_are_not_waited_on() {
+        let mut buffer = new(8, LONG_WAIT);
+        buffer.push(frame(1));
+        buffer.push(frame(3));
+        buffer.skip(0);
+        buffer.skip(2);
+
+        assert_eq!(seqs(buffer.pop_ready()), vec![1, 3]);
+        assert_eq!(buffer.next_seq(), 4);
+    }
+
+    #[test]
+    fn gives_up_on_a_frame_past_the_deadline() {
+        let mut buffer = new(8, Duration::ZERO);
+        buffer.push(frame(2));
+
+        assert_eq!(seqs(buffer.pop_ready()), vec![2]);
+        assert_eq!(buffer.next_seq(), 3);
+    }
+
+    #[test]
+    fn gives_up_on_a_frame_when_full() {
+        let mut buffer = new(2, LONG_WAIT);
+        buffer.push(frame(1));
+        assert!(buffer.pop_ready().is_empty());
+
+        buffer.push(frame(2));
+        assert_eq!(seqs(buffer.pop_ready()), vec![1, 2]);
+    }
+
+    #[test]
+    fn drops_frames_that_arrive_too_late() {
+        let mut buffer = new(8, Duration::ZERO);
+        buffer.push(frame(1));
+        assert_eq!(seqs(buffer.pop_ready()), vec![1]);
+
+        buffer.push(frame(0));
+        assert!(buffer.pop_ready().is_empty());
+    }
+
+    #[test]
+    fn flush_releases_everything_in_order() {
+        let mut buffer = new(8, LONG_WAIT);
+        buffer.push(frame(4));
+        buffer.push(frame(2));
+        buffer.skip(3);
+
+        assert!(buffer.pop_ready().is_empty());
+        assert_eq!(seqs(buffer.flush()), vec![2, 4]);
+        assert_eq!(buffer.next_seq(), 5);
+        assert!(buffer.time_until_deadline().is_none());
+    }
+}
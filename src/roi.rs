@@ -1,27 +1,233 @@
 use opencv::core::{Rect_, Vector};
-use std::collections::{hash_map::IntoIter, HashMap};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{hash_map::IntoIter, BTreeMap, HashMap};
 
-#[derive(Clone, Debug)]
+use crate::{
+    img::preprocess::{self, Graph},
+    reading::{Candidate, Reading},
+};
+
+/// What a region shows, which decides how its text is corrected and parsed.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum RegionOfInterestType {
-    Text,
+    /// Any text, as read.
+    #[serde(alias = "text")]
+    FreeText,
+    /// A whole number, bounded by the region's `min` and `max` options if set.
+    Integer,
+    /// A run of digits where leading zeros matter.
+    Digits,
+    /// One of the words in the named vocabulary.
+    Enum(String),
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize)]
 pub struct RegionOfInterest {
     pub x: i32,
     pub y: i32,
     pub width: i32,
     pub height: i32,
+    #[serde(skip)]
+    pub bounds: NormalizedRect,
+    #[serde(rename = "type")]
     pub roi_type: RegionOfInterestType,
     pub result: Option<String>,
+    /// How sure the recognizer was of `result`, from 0 to 1.
+    pub confidence: Option<f64>,
+    /// `result` parsed according to `roi_type`.
+    pub reading: Option<Reading>,
+    /// The next best word for regions decoded against a vocabulary.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub runner_up: Option<Candidate>,
     pub name: String,
+    #[serde(skip)]
     pub base_resolution: StreamResolution,
+    #[serde(skip)]
+    pub options: BTreeMap<String, String>,
+    /// Run on the region's crop before it's read, unless the region picks a
+    /// named graph with a `graph` option.
+    #[serde(skip)]
+    pub preprocess: Graph,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct StreamResolution {
+    pub width: i32,
+    pub height: i32,
+}
+
+impl StreamResolution {
+    pub const HD720P: StreamResolution = StreamResolution::new(1280, 720);
+    pub const HD900P: StreamResolution = StreamResolution::new(1600, 900);
+    pub const HD1080P: StreamResolution = StreamResolution::new(1920, 1080);
+    pub const QHD1440P: StreamResolution = StreamResolution::new(2560, 1440);
+    pub const UHD2160P: StreamResolution = StreamResolution::new(3840, 2160);
+
+    pub const fn new(width: i32, height: i32) -> StreamResolution {
+        StreamResolution { width, height }
+    }
+
+    pub fn aspect_ratio(&self) -> f64 {
+        self.width as f64 / self.height as f64
+    }
+
+    /// The largest area with the aspect ratio of `base` that fits centered in
+    /// this resolution, as `(x, y, width, height)`.
+    ///
+    /// Sources with a different aspect ratio are assumed to be letterboxed or
+    /// pillarboxed, so regions land on the picture instead of on the bars.
+    pub fn picture_area(&self, base: &StreamResolution) -> (f64, f64, f64, f64) {
+        let scale =
+            (self.width as f64 / base.width as f64).min(self.height as f64 / base.height as f64);
+        let width = base.width as f64 * scale;
+        let height = base.height as f64 * scale;
+
+        (
+            (self.width as f64 - width) / 2.,
+            (self.height as f64 - height) / 2.,
+            width,
+            height,
+        )
+    }
+}
+
+/// The point of the picture a `NormalizedRect` is measured from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Anchor {
+    #[default]
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl Anchor {
+    fn horizontal(&self) -> Alignment {
+        match self {
+            Anchor::TopLeft | Anchor::Left | Anchor::BottomLeft => Alignment::Start,
+            Anchor::Top | Anchor::Center | Anchor::Bottom => Alignment::Middle,
+            Anchor::TopRight | Anchor::Right | Anchor::BottomRight => Alignment::End,
+        }
+    }
+
+    fn vertical(&self) -> Alignment {
+        match self {
+            Anchor::TopLeft | Anchor::Top | Anchor::TopRight => Alignment::Start,
+            Anchor::Left | Anchor::Center | Anchor::Right => Alignment::Middle,
+            Anchor::BottomLeft | Anchor::Bottom | Anchor::BottomRight => Alignment::End,
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
-pub enum StreamResolution {
-    HD720p,
-    HD1080p,
+enum Alignment {
+    Start,
+    Middle,
+    End,
+}
+
+impl Alignment {
+    fn place(&self, offset: f64, size: f64, picture_start: f64, picture_size: f64) -> f64 {
+        match self {
+            Alignment::Start => picture_start + offset * picture_size,
+            Alignment::Middle => {
+                picture_start + (0.5 + offset) * picture_size - size * picture_size / 2.
+            }
+            Alignment::End => picture_start + (1. - offset - size) * picture_size,
+        }
+    }
+
+    fn fits(&self, offset: f64, size: f64) -> bool {
+        match self {
+            Alignment::Middle => offset.abs() + size / 2. <= 0.5 + f64::EPSILON,
+            Alignment::Start | Alignment::End => offset >= 0. && offset + size <= 1. + f64::EPSILON,
+        }
+    }
+}
+
+/// A rect in 0..1 units of the picture width and height.
+///
+/// `x` and `y` are the distance from `anchor` to the nearest edge of the rect,
+/// so a `BottomRight` rect with `x: 0.05` ends 5% of the width from the right
+/// edge. For centered anchors they are the offset between the two centers.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NormalizedRect {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+    #[serde(default)]
+    pub anchor: Anchor,
+}
+
+impl NormalizedRect {
+    pub fn new(anchor: Anchor, x: f64, y: f64, width: f64, height: f64) -> NormalizedRect {
+        NormalizedRect {
+            x,
+            y,
+            width,
+            height,
+            anchor,
+        }
+    }
+
+    pub fn from_pixels(
+        x: i32,
+        y: i32,
+        width: i32,
+        height: i32,
+        res: &StreamResolution,
+    ) -> NormalizedRect {
+        NormalizedRect {
+            x: x as f64 / res.width as f64,
+            y: y as f64 / res.height as f64,
+            width: width as f64 / res.width as f64,
+            height: height as f64 / res.height as f64,
+            anchor: Anchor::TopLeft,
+        }
+    }
+
+    /// Whether the rect has a size and lies entirely inside the picture.
+    pub fn is_within_picture(&self) -> bool {
+        self.width > 0.
+            && self.height > 0.
+            && self.anchor.horizontal().fits(self.x, self.width)
+            && self.anchor.vertical().fits(self.y, self.height)
+    }
+
+    /// Resolves the rect to `(x, y, width, height)` pixels of a frame with
+    /// `resolution`, clamped to the frame.
+    pub fn to_pixels(
+        &self,
+        resolution: &StreamResolution,
+        base: &StreamResolution,
+    ) -> (i32, i32, i32, i32) {
+        let (picture_x, picture_y, picture_width, picture_height) = resolution.picture_area(base);
+
+        let left = self
+            .anchor
+            .horizontal()
+            .place(self.x, self.width, picture_x, picture_width);
+        let top = self
+            .anchor
+            .vertical()
+            .place(self.y, self.height, picture_y, picture_height);
+
+        let x = (left.round() as i32).clamp(0, resolution.width - 1);
+        let y = (top.round() as i32).clamp(0, resolution.height - 1);
+        let width = ((self.width * picture_width).round() as i32).clamp(1, resolution.width - x);
+        let height =
+            ((self.height * picture_height).round() as i32).clamp(1, resolution.height - y);
+
+        (x, y, width, height)
+    }
 }
 
 pub fn new_region(
@@ -32,15 +238,35 @@ pub fn new_region(
     height: i32,
     res: StreamResolution,
 ) -> RegionOfInterest {
+    new_normalized_region(
+        name,
+        NormalizedRect::from_pixels(x, y, width, height, &res),
+        res,
+    )
+}
+
+pub fn new_normalized_region(
+    name: String,
+    bounds: NormalizedRect,
+    res: StreamResolution,
+) -> RegionOfInterest {
+    let (x, y, width, height) = bounds.to_pixels(&res, &res);
+
     RegionOfInterest {
         x,
         y,
         width,
         height,
-        roi_type: RegionOfInterestType::Text,
+        bounds,
+        roi_type: RegionOfInterestType::FreeText,
         result: None,
+        confidence: None,
+        reading: None,
+        runner_up: None,
         name,
         base_resolution: res,
+        options: BTreeMap::new(),
+        preprocess: preprocess::default_graph(),
     }
 }
 
@@ -48,6 +274,19 @@ impl RegionOfInterest {
     pub fn set_result(&mut self, result: String) {
         self.result = Some(result);
     }
+
+    /// Resolves the region's bounds to pixels of a frame with `resolution`.
+    pub fn resolve(&self, resolution: &StreamResolution) -> RegionOfInterest {
+        let (x, y, width, height) = self.bounds.to_pixels(resolution, &self.base_resolution);
+
+        RegionOfInterest {
+            x,
+            y,
+            width,
+            height,
+            ..self.clone()
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -61,6 +300,12 @@ pub fn new_region_list() -> RegionOfInterestList {
     }
 }
 
+impl Serialize for RegionOfInterestList {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.list.iter().collect::<BTreeMap<_, _>>())
+    }
+}
+
 impl RegionOfInterestList {
     pub fn add_region(&mut self, region: RegionOfInterest) {
         self.list.insert(region.clone().name, region);
@@ -80,6 +325,17 @@ impl RegionOfInterestList {
         self.add_region(region);
     }
 
+    pub fn add_normalized_region(
+        &mut self,
+        name: String,
+        bounds: NormalizedRect,
+        res: StreamResolution,
+    ) {
+        let region = new_normalized_region(name, bounds, res);
+
+        self.add_region(region);
+    }
+
     pub fn get_value(&self, region_name: String) -> Option<&RegionOfInterest> {
         self.list.get(&region_name)
     }
@@ -103,6 +359,32 @@ impl RegionOfInterestList {
             .collect()
     }
 
+    pub fn resolve(&self, resolution: &StreamResolution) -> RegionOfInterestList {
+        RegionOfInterestList {
+            list: self
+                .list
+                .iter()
+                .map(|(name, region)| (name.clone(), region.resolve(resolution)))
+                .collect(),
+        }
+    }
+
+    /// The smallest rect covering every region, `None` if there are none.
+    pub fn bounding_rect(&self) -> Option<Rect_<i32>> {
+        self.list
+            .values()
+            .map(|region| {
+                (
+                    region.x,
+                    region.y,
+                    region.x + region.width,
+                    region.y + region.height,
+                )
+            })
+            .reduce(|a, b| (a.0.min(b.0), a.1.min(b.1), a.2.max(b.2), a.3.max(b.3)))
+            .map(|(left, top, right, bottom)| Rect_::new(left, top, right - left, bottom - top))
+    }
+
     pub fn vec_of_rects(&self) -> Vector<Rect_<i32>> {
         self.list
             .iter()
@@ -110,3 +392,60 @@ impl RegionOfInterestList {
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FULL_HD: StreamResolution = StreamResolution::HD1080P;
+
+    #[test]
+    fn pixel_rects_round_trip() {
+        let rect = NormalizedRect::from_pixels(1720, 998, 62, 30, &FULL_HD);
+
+        assert_eq!(rect.to_pixels(&FULL_HD, &FULL_HD), (1720, 998, 62, 30));
+    }
+
+    #[test]
+    fn rects_scale_with_the_stream() {
+        let rect = NormalizedRect::from_pixels(1720, 998, 62, 30, &FULL_HD);
+
+        assert_eq!(
+            rect.to_pixels(&StreamResolution::HD720P, &FULL_HD),
+            (1147, 665, 41, 20)
+        );
+        assert_eq!(
+            rect.to_pixels(&StreamResolution::UHD2160P, &FULL_HD),
+            (3440, 1996, 124, 60)
+        );
+    }
+
+    #[test]
+    fn anchors_measure_from_their_edge() {
+        let bottom_right =
+            NormalizedRect::new(Anchor::BottomRight, 0.071875, 0.074074, 0.032292, 0.037037);
+        assert_eq!(
+            bottom_right.to_pixels(&FULL_HD, &FULL_HD),
+            (1720, 960, 62, 40)
+        );
+
+        let center = NormalizedRect::new(Anchor::Center, 0., 0., 0.1, 0.1);
+        assert_eq!(center.to_pixels(&FULL_HD, &FULL_HD), (864, 486, 192, 108));
+    }
+
+    #[test]
+    fn letterboxed_streams_map_onto_the_picture() {
+        let rect = NormalizedRect::new(Anchor::TopLeft, 0., 0., 0.5, 0.5);
+        let four_by_three = StreamResolution::new(1440, 1080);
+
+        assert_eq!(rect.to_pixels(&four_by_three, &FULL_HD), (0, 135, 720, 405));
+    }
+
+    #[test]
+    fn rects_are_clamped_to_the_frame() {
+        let rect = NormalizedRect::new(Anchor::TopLeft, 0.95, 0., 0.1, 0.1);
+
+        assert!(!rect.is_within_picture());
+        assert_eq!(rect.to_pixels(&FULL_HD, &FULL_HD), (1824, 0, 96, 108));
+    }
+}
@@ -0,0 +1,156 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{self, LineWriter, Write},
+};
+
+use crate::{
+    games::{GameData, SupportedGames},
+    roi::RegionOfInterestList,
+    state::MatchPhase,
+    Config,
+};
+
+/// Everything known about a frame once it has been processed.
+#[derive(Debug, Serialize)]
+pub struct FrameRecord {
+    pub num: i64,
+    pub pts: Option<i64>,
+    /// Seconds from the start of the source video.
+    pub timestamp: Option<f64>,
+    pub received_at: DateTime<Utc>,
+    pub results: RegionOfInterestList,
+    pub data: GameData,
+    /// The match phase the frame was read in, for games that have them.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phase: Option<MatchPhase>,
+}
+
+/// The match phase changing, as of the frame that settled it.
+#[derive(Debug, Clone, Serialize)]
+pub struct PhaseRecord {
+    pub num: i64,
+    pub timestamp: Option<f64>,
+    /// `None` for the first phase seen.
+    pub from: Option<MatchPhase>,
+    pub to: MatchPhase,
+}
+
+/// A stretch of a network stream that was lost while reconnecting.
+#[derive(Debug, Clone, Serialize)]
+pub struct GapRecord {
+    /// Frames from this sequence number on were decoded after the gap.
+    #[serde(skip)]
+    pub seq: i64,
+    pub reason: String,
+    /// Timestamp of the last frame before the gap.
+    pub last_timestamp: Option<f64>,
+    pub started_at: DateTime<Utc>,
+    /// When the stream came back, `None` if it never did.
+    pub resumed_at: Option<DateTime<Utc>>,
+    pub reconnect_attempts: u32,
+}
+
+/// A stretch of video where no known game's HUD was on screen, so no frames
+/// were read.
+#[derive(Debug, Clone, Serialize)]
+pub struct PauseRecord {
+    /// Timestamp of the probe that found no HUD.
+    pub paused_at: Option<f64>,
+    /// Timestamp of the probe that found one again, `None` if the video
+    /// ended first.
+    pub resumed_at: Option<f64>,
+    /// The game on screen before the pause.
+    pub before: Option<SupportedGames>,
+    /// The game on screen after it.
+    pub after: Option<SupportedGames>,
+}
+
+/// Somewhere processed frames are sent to. Every sink sees every frame, in
+/// frame order.
+pub trait ResultSink: Send {
+    fn write_frame(&mut self, frame: &FrameRecord) -> io::Result<()>;
+
+    fn write_gap(&mut self, _gap: &GapRecord) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_phase(&mut self, _phase: &PhaseRecord) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_pause(&mut self, _pause: &PauseRecord) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes one JSON object per frame, gap, pause or phase change, one per
+/// line, told apart by their `event` field.
+pub struct JsonLinesSink {
+    writer: Box<dyn Write + Send>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum JsonLine<'a> {
+    Frame(&'a FrameRecord),
+    Gap(&'a GapRecord),
+    Phase(&'a PhaseRecord),
+    Pause(&'a PauseRecord),
+}
+
+impl JsonLinesSink {
+    fn write_line(&mut self, line: &JsonLine) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, line)?;
+        self.writer.write_all(b"\n")
+    }
+}
+
+/// Opens a JSON Lines sink writing to `target`, or to stdout if it is `-`.
+pub fn json_lines(target: &str) -> io::Result<JsonLinesSink> {
+    let writer: Box<dyn Write + Send> = if target == "-" {
+        Box::new(LineWriter::new(io::stdout()))
+    } else {
+        Box::new(LineWriter::new(File::create(target)?))
+    };
+
+    Ok(JsonLinesSink { writer })
+}
+
+impl ResultSink for JsonLinesSink {
+    fn write_frame(&mut self, frame: &FrameRecord) -> io::Result<()> {
+        self.write_line(&JsonLine::Frame(frame))
+    }
+
+    fn write_gap(&mut self, gap: &GapRecord) -> io::Result<()> {
+        self.write_line(&JsonLine::Gap(gap))
+    }
+
+    fn write_phase(&mut self, phase: &PhaseRecord) -> io::Result<()> {
+        self.write_line(&JsonLine::Phase(phase))
+    }
+
+    fn write_pause(&mut self, pause: &PauseRecord) -> io::Result<()> {
+        self.write_line(&JsonLine::Pause(pause))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+pub fn from_config(config: &Config) -> io::Result<Vec<Box<dyn ResultSink>>> {
+    let mut sinks: Vec<Box<dyn ResultSink>> = Vec::new();
+
+    for target in &config.jsonl {
+        info!("writing results as json lines to {}", target);
+        sinks.push(Box::new(json_lines(target)?));
+    }
+
+    Ok(sinks)
+}
@@ -1,18 +1,637 @@
-use std::{collections::VecDeque, sync::Arc};
+use enum_iterator::Sequence;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::Arc,
+};
 
 use tokio::sync::Mutex;
 
-pub type StringWindow = Arc<Mutex<VecDeque<String>>>;
+use crate::{
+    error::{Result, StreamCvError},
+    reading::{Candidate, Reading},
+    roi::{self, RegionOfInterest, RegionOfInterestList},
+};
+
+/// Share of a screen's marker regions that have to be read for a frame to
+/// show it.
+const MIN_MARKER_SHARE: f64 = 0.5;
+
+/// Where the player is in a match, as far as the screen shows.
+///
+/// Regions can be limited to some phases with a `phases` option listing
+/// them, like `phases = "in_match,spectating"`, and are read in every phase
+/// without one. A `screen` option makes a region a marker of the phase it
+/// names: a frame shows the phase when most of its markers were read.
+///
+/// Phases only change along the course of a match:
+///
+/// ```text
+/// lobby -> legend_select -> dropship -> in_match <-> spectating
+///   ^                                      |            |
+///   +------------- death_recap <-----------+------------+
+/// ```
+///
+/// A player can die or win without spectating, so `in_match` leads to
+/// `death_recap` directly too. Any phase can go back to `lobby`, as players
+/// can leave a match at any point and a missed death recap shouldn't hold
+/// the phase in the match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Sequence)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchPhase {
+    Lobby,
+    LegendSelect,
+    Dropship,
+    InMatch,
+    Spectating,
+    DeathRecap,
+}
+
+impl fmt::Display for MatchPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            MatchPhase::Lobby => "lobby",
+            MatchPhase::LegendSelect => "legend_select",
+            MatchPhase::Dropship => "dropship",
+            MatchPhase::InMatch => "in_match",
+            MatchPhase::Spectating => "spectating",
+            MatchPhase::DeathRecap => "death_recap",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+impl MatchPhase {
+    /// Phases in the order frames are checked for them. Screens drawn over
+    /// the HUD come before it, as its regions still read under them.
+    const PRECEDENCE: [MatchPhase; 6] = [
+        MatchPhase::DeathRecap,
+        MatchPhase::Spectating,
+        MatchPhase::LegendSelect,
+        MatchPhase::Dropship,
+        MatchPhase::Lobby,
+        MatchPhase::InMatch,
+    ];
+
+    /// The phases the phase can change to.
+    pub fn successors(&self) -> &'static [MatchPhase] {
+        match self {
+            MatchPhase::Lobby => &[MatchPhase::LegendSelect],
+            MatchPhase::LegendSelect => &[MatchPhase::Dropship, MatchPhase::Lobby],
+            MatchPhase::Dropship => &[MatchPhase::InMatch, MatchPhase::Lobby],
+            MatchPhase::InMatch => &[
+                MatchPhase::Spectating,
+                MatchPhase::DeathRecap,
+                MatchPhase::Lobby,
+            ],
+            MatchPhase::Spectating => &[
+                MatchPhase::InMatch,
+                MatchPhase::DeathRecap,
+                MatchPhase::Lobby,
+            ],
+            MatchPhase::DeathRecap => &[MatchPhase::Lobby],
+        }
+    }
+
+    fn from_name(name: &str) -> Option<MatchPhase> {
+        enum_iterator::all::<MatchPhase>().find(|phase| phase.to_string() == name.trim())
+    }
+
+    /// Whether `region` is read during the phase.
+    pub fn reads(&self, region: &RegionOfInterest) -> bool {
+        region.options.get("phases").is_none_or(|phases| {
+            phases
+                .split(',')
+                .any(|name| name.trim() == self.to_string())
+        })
+    }
+
+    /// The regions of `regions` read during the phase.
+    pub fn regions(&self, regions: &RegionOfInterestList) -> RegionOfInterestList {
+        let mut read = roi::new_region_list();
+        for (_, region) in regions.iter() {
+            if self.reads(&region) {
+                read.add_region(region);
+            }
+        }
+
+        read
+    }
+
+    /// Share of the phase's marker regions read in `results`, `None` if the
+    /// phase has none.
+    fn marker_share(&self, results: &RegionOfInterestList) -> Option<f64> {
+        let markers: Vec<RegionOfInterest> = results
+            .iter()
+            .map(|(_, region)| region)
+            .filter(|region| {
+                region
+                    .options
+                    .get("screen")
+                    .and_then(|name| MatchPhase::from_name(name))
+                    == Some(*self)
+            })
+            .collect();
+        if markers.is_empty() {
+            return None;
+        }
+
+        let read = markers
+            .iter()
+            .filter(|region| matches!(region.reading, Some(Reading::Parsed { .. })))
+            .count();
+
+        Some(read as f64 / markers.len() as f64)
+    }
+}
+
+/// The phase a frame's reads show, `None` if they show none.
+pub fn classify_phase(results: &RegionOfInterestList) -> Option<MatchPhase> {
+    MatchPhase::PRECEDENCE.into_iter().find(|phase| {
+        phase
+            .marker_share(results)
+            .is_some_and(|share| share >= MIN_MARKER_SHARE)
+    })
+}
+
+/// Fails if a region's `phases` or `screen` option names a phase that
+/// doesn't exist.
+pub fn check_phases(regions: &RegionOfInterestList) -> Result<()> {
+    for (name, region) in regions.iter() {
+        let phases = region
+            .options
+            .get("phases")
+            .into_iter()
+            .flat_map(|p| p.split(','));
+        let screen = region.options.get("screen").map(String::as_str);
+
+        for phase in phases.chain(screen) {
+            if MatchPhase::from_name(phase).is_none() {
+                return Err(StreamCvError::Config(format!(
+                    "region {} names phase {}, which doesn't exist",
+                    name,
+                    phase.trim()
+                )));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// What a region read in one frame.
+#[derive(Clone, Debug)]
+pub struct Read {
+    pub text: String,
+    pub confidence: Option<f64>,
+    pub reading: Option<Reading>,
+    pub runner_up: Option<Candidate>,
+}
+
+impl Read {
+    /// How much the read counts towards its value, its reading's
+    /// confidence if it was parsed, so failed readings don't count at all.
+    fn weight(&self) -> f64 {
+        match &self.reading {
+            Some(reading) => reading.confidence(),
+            None => self.confidence.unwrap_or(1.),
+        }
+    }
+}
+
+/// The most recent reads of a region, oldest first. Frames where nothing was
+/// read are kept as empty reads so stale values age out.
+pub type ReadWindow = VecDeque<Read>;
+
+/// The match phase frames are read in, and the phase the latest frames
+/// showed instead with how many did.
+#[derive(Debug, Default)]
+struct PhaseTracker {
+    phase: Option<MatchPhase>,
+    candidate: Option<MatchPhase>,
+    seen: usize,
+}
+
+impl PhaseTracker {
+    /// Counts a frame showing `shown`, returning the phase changed from and
+    /// to once `frames` frames in a row show the same new one. Before the
+    /// first phase any can be taken, after it only its successors; frames
+    /// showing another phase, or none, don't count either way.
+    fn observe(
+        &mut self,
+        shown: Option<MatchPhase>,
+        frames: usize,
+    ) -> Option<(Option<MatchPhase>, MatchPhase)> {
+        let shown = shown?;
+
+        if self.phase == Some(shown) {
+            self.candidate = None;
+            self.seen = 0;
+            return None;
+        }
+        if let Some(phase) = self.phase {
+            if !phase.successors().contains(&shown) {
+                return None;
+            }
+        }
+
+        if self.candidate == Some(shown) {
+            self.seen += 1;
+        } else {
+            self.candidate = Some(shown);
+            self.seen = 1;
+        }
+        if self.seen < frames {
+            return None;
+        }
+
+        let from = self.phase.replace(shown);
+        self.candidate = None;
+        self.seen = 0;
+
+        Some((from, shown))
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct GameState {
-    _weapon1: StringWindow,
-    _weapon2: StringWindow,
+    windows: Arc<Mutex<HashMap<String, ReadWindow>>>,
+    phase: Arc<Mutex<PhaseTracker>>,
+    window_length: usize,
+    agreement: f64,
+    phase_frames: usize,
 }
 
-pub fn new() -> GameState {
+/// Creates a state that takes `phase_frames` frames showing a new match
+/// phase before changing to it.
+pub fn new(window_length: usize, agreement: f64, phase_frames: usize) -> GameState {
     GameState {
-        _weapon1: Arc::new(Mutex::new(VecDeque::new())),
-        _weapon2: Arc::new(Mutex::new(VecDeque::new())),
+        windows: Arc::new(Mutex::new(HashMap::new())),
+        phase: Arc::new(Mutex::new(PhaseTracker::default())),
+        window_length: window_length.max(1),
+        agreement,
+        phase_frames: phase_frames.max(1),
+    }
+}
+
+impl GameState {
+    /// Forgets every read and the match phase, for when another game's HUD
+    /// comes on screen.
+    pub async fn reset(&self) {
+        self.windows.lock().await.clear();
+        *self.phase.lock().await = PhaseTracker::default();
+    }
+
+    /// The match phase frames are read in, `None` until one has been seen.
+    pub async fn phase(&self) -> Option<MatchPhase> {
+        self.phase.lock().await.phase
+    }
+
+    /// Counts a frame showing `shown` towards a change of phase, returning
+    /// the phase changed from and to once enough frames in a row show the
+    /// same new one that can follow the current phase.
+    pub async fn observe_phase(
+        &self,
+        shown: Option<MatchPhase>,
+    ) -> Option<(Option<MatchPhase>, MatchPhase)> {
+        let change = self.phase.lock().await.observe(shown, self.phase_frames)?;
+
+        // what was read on the last screen doesn't carry over to the next
+        self.windows.lock().await.clear();
+
+        Some(change)
+    }
+
+    /// Adds the reads in `results` to each region's window and returns the
+    /// regions with the value the window agrees on.
+    ///
+    /// The value with the most confidence over the window wins, and is only
+    /// reported once it was read in at least `agreement` of a full window,
+    /// otherwise the region's result is `None`. Failed reads, including those
+    /// below the confidence floor, don't vote at all. The agreed on value
+    /// keeps the confidence and reading of its most recent read.
+    pub async fn smooth(&self, results: &RegionOfInterestList) -> RegionOfInterestList {
+        let mut windows = self.windows.lock().await;
+        let mut smoothed = roi::new_region_list();
+
+        for (name, mut region) in results.iter() {
+            let window = windows.entry(name).or_insert_with(VecDeque::new);
+
+            window.push_back(Read {
+                text: region.result.clone().unwrap_or_default(),
+                confidence: region.confidence,
+                reading: region.reading.clone(),
+                runner_up: region.runner_up.clone(),
+            });
+            while window.len() > self.window_length {
+                window.pop_front();
+            }
+
+            let read = self.consensus(window);
+            region.result = read.as_ref().map(|read| read.text.clone());
+            region.confidence = read.as_ref().and_then(|read| read.confidence);
+            region.runner_up = read.as_ref().and_then(|read| read.runner_up.clone());
+            region.reading = read.and_then(|read| read.reading);
+            smoothed.add_region(region);
+        }
+
+        smoothed
+    }
+
+    fn consensus(&self, window: &ReadWindow) -> Option<Read> {
+        // each value's total weight, its number of reads and the position of
+        // its latest read
+        let mut votes: HashMap<&str, (f64, usize, usize)> = HashMap::new();
+        for (i, read) in window.iter().enumerate() {
+            let weight = read.weight();
+            if read.text.is_empty() || weight <= 0. {
+                continue;
+            }
+            let vote = votes.entry(read.text.as_str()).or_insert((0., 0, i));
+            vote.0 += weight;
+            vote.1 += 1;
+            vote.2 = i;
+        }
+
+        // ties go to the value read most recently
+        let (value, (_, count, _)) = votes.into_iter().max_by(
+            |(_, (weight_a, _, last_a)), (_, (weight_b, _, last_b))| {
+                weight_a.total_cmp(weight_b).then(last_a.cmp(last_b))
+            },
+        )?;
+
+        if count as f64 / self.window_length as f64 >= self.agreement {
+            window.iter().rev().find(|read| read.text == value).cloned()
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{reading::Value, roi::StreamResolution};
+
+    fn read(name: &str, text: &str, confidence: f64) -> RegionOfInterest {
+        let mut region = roi::new_region(name.to_string(), 0, 0, 10, 10, StreamResolution::HD1080P);
+        if !text.is_empty() {
+            region.set_result(text.to_string());
+            region.confidence = Some(confidence);
+            region.reading = Some(Reading::Parsed {
+                value: Value::Text(text.to_string()),
+                confidence,
+            });
+        }
+
+        region
+    }
+
+    async fn smoothed(state: &GameState, text: &str, confidence: f64) -> Option<String> {
+        let mut results = roi::new_region_list();
+        results.add_region(read("ammo", text, confidence));
+
+        state
+            .smooth(&results)
+            .await
+            .get_value("ammo".to_string())
+            .and_then(|region| region.result.clone())
+    }
+
+    #[tokio::test]
+    async fn values_wait_for_the_window_to_agree() {
+        let state = new(5, 0.6, 10);
+
+        assert_eq!(smoothed(&state, "12", 1.).await, None);
+        assert_eq!(smoothed(&state, "12", 1.).await, None);
+        assert_eq!(smoothed(&state, "12", 1.).await, Some("12".to_string()));
+    }
+
+    #[tokio::test]
+    async fn outliers_are_outvoted() {
+        let state = new(5, 0.6, 10);
+        for _ in 0..4 {
+            smoothed(&state, "12", 1.).await;
+        }
+
+        assert_eq!(smoothed(&state, "72", 1.).await, Some("12".to_string()));
+    }
+
+    #[tokio::test]
+    async fn confident_reads_count_for_more() {
+        let state = new(4, 0.5, 10);
+        smoothed(&state, "12", 0.9).await;
+        smoothed(&state, "12", 0.9).await;
+        smoothed(&state, "13", 0.3).await;
+
+        // as many reads each, but 12's were more confident
+        assert_eq!(smoothed(&state, "13", 0.3).await, Some("12".to_string()));
+    }
+
+    #[tokio::test]
+    async fn agreement_doesnt_depend_on_confidence() {
+        let state = new(5, 0.6, 10);
+        smoothed(&state, "12", 0.55).await;
+        smoothed(&state, "12", 0.55).await;
+
+        assert_eq!(smoothed(&state, "12", 0.55).await, Some("12".to_string()));
+        assert_eq!(smoothed(&state, "12", 0.55).await, Some("12".to_string()));
+        assert_eq!(smoothed(&state, "12", 0.55).await, Some("12".to_string()));
+    }
+
+    #[tokio::test]
+    async fn failed_reads_dont_vote() {
+        let state = new(3, 0.6, 10);
+        smoothed(&state, "12", 1.).await;
+        smoothed(&state, "12", 0.).await;
+
+        assert_eq!(smoothed(&state, "12", 0.).await, None);
+    }
+
+    #[tokio::test]
+    async fn ties_go_to_the_latest_read() {
+        let state = new(4, 0.5, 10);
+        smoothed(&state, "a", 1.).await;
+        smoothed(&state, "a", 1.).await;
+        smoothed(&state, "b", 1.).await;
+
+        assert_eq!(smoothed(&state, "b", 1.).await, Some("b".to_string()));
+        assert_eq!(smoothed(&state, "a", 1.).await, Some("a".to_string()));
+    }
+
+    #[tokio::test]
+    async fn empty_reads_age_values_out() {
+        let state = new(3, 0.6, 10);
+        smoothed(&state, "12", 1.).await;
+        assert_eq!(smoothed(&state, "12", 1.).await, Some("12".to_string()));
+
+        assert_eq!(smoothed(&state, "", 0.).await, Some("12".to_string()));
+        assert_eq!(smoothed(&state, "", 0.).await, None);
+    }
+
+    fn marker(name: &str, screen: MatchPhase, seen: bool) -> RegionOfInterest {
+        let mut region = read(name, if seen { "marker" } else { "" }, 1.);
+        region
+            .options
+            .insert("screen".to_string(), screen.to_string());
+
+        region
+    }
+
+    fn frame(markers: Vec<RegionOfInterest>) -> RegionOfInterestList {
+        let mut results = roi::new_region_list();
+        for marker in markers {
+            results.add_region(marker);
+        }
+
+        results
+    }
+
+    #[test]
+    fn phases_show_when_most_markers_are_read() {
+        let half = frame(vec![
+            marker("play", MatchPhase::Lobby, true),
+            marker("ready", MatchPhase::Lobby, false),
+        ]);
+        assert_eq!(classify_phase(&half), Some(MatchPhase::Lobby));
+
+        let few = frame(vec![
+            marker("play", MatchPhase::Lobby, true),
+            marker("ready", MatchPhase::Lobby, false),
+            marker("squad", MatchPhase::Lobby, false),
+        ]);
+        assert_eq!(classify_phase(&few), None);
+    }
+
+    #[test]
+    fn screens_over_the_hud_come_first() {
+        let results = frame(vec![
+            marker("compass", MatchPhase::InMatch, true),
+            marker("summary", MatchPhase::DeathRecap, true),
+        ]);
+
+        assert_eq!(classify_phase(&results), Some(MatchPhase::DeathRecap));
+    }
+
+    #[test]
+    fn regions_are_limited_to_their_phases() {
+        let mut ammo = read("ammo", "12", 1.);
+        ammo.options
+            .insert("phases".to_string(), "in_match, spectating".to_string());
+        let results = frame(vec![ammo, read("clock", "1:00", 1.)]);
+
+        assert_eq!(MatchPhase::Spectating.regions(&results).len(), 2);
+        assert_eq!(MatchPhase::Lobby.regions(&results).len(), 1);
+    }
+
+    #[test]
+    fn unknown_phases_are_rejected() {
+        let mut region = read("ammo", "", 1.);
+        region
+            .options
+            .insert("phases".to_string(), "in_match,halftime".to_string());
+
+        assert!(check_phases(&frame(vec![region])).is_err());
+    }
+
+    #[test]
+    fn phases_change_after_enough_frames() {
+        let mut tracker = PhaseTracker::default();
+
+        assert_eq!(tracker.observe(Some(MatchPhase::InMatch), 3), None);
+        assert_eq!(tracker.observe(Some(MatchPhase::InMatch), 3), None);
+        assert_eq!(
+            tracker.observe(Some(MatchPhase::InMatch), 3),
+            Some((None, MatchPhase::InMatch))
+        );
+        assert_eq!(tracker.observe(Some(MatchPhase::InMatch), 3), None);
+    }
+
+    #[test]
+    fn phases_only_change_to_their_successors() {
+        let mut tracker = PhaseTracker::default();
+        tracker.observe(Some(MatchPhase::InMatch), 1);
+
+        for _ in 0..5 {
+            assert_eq!(tracker.observe(Some(MatchPhase::LegendSelect), 1), None);
+        }
+        assert_eq!(
+            tracker.observe(Some(MatchPhase::DeathRecap), 1),
+            Some((Some(MatchPhase::InMatch), MatchPhase::DeathRecap))
+        );
+    }
+
+    #[test]
+    fn the_current_phase_interrupts_a_change() {
+        let mut tracker = PhaseTracker::default();
+        tracker.observe(Some(MatchPhase::InMatch), 1);
+
+        assert_eq!(tracker.observe(Some(MatchPhase::Spectating), 2), None);
+        assert_eq!(tracker.observe(Some(MatchPhase::InMatch), 2), None);
+        assert_eq!(tracker.observe(Some(MatchPhase::Spectating), 2), None);
+        // frames showing no phase don't interrupt it
+        assert_eq!(tracker.observe(None, 2), None);
+        assert_eq!(
+            tracker.observe(Some(MatchPhase::Spectating), 2),
+            Some((Some(MatchPhase::InMatch), MatchPhase::Spectating))
+        );
+    }
+
+    #[test]
+    fn players_can_leave_for_the_lobby_from_any_phase() {
+        let mut tracker = PhaseTracker::default();
+        tracker.observe(Some(MatchPhase::InMatch), 1);
+        tracker.observe(Some(MatchPhase::Spectating), 1);
+
+        // the death recap was missed, the lobby shows next
+        assert_eq!(
+            tracker.observe(Some(MatchPhase::Lobby), 1),
+            Some((Some(MatchPhase::Spectating), MatchPhase::Lobby))
+        );
+        assert_eq!(
+            tracker.observe(Some(MatchPhase::LegendSelect), 1),
+            Some((Some(MatchPhase::Lobby), MatchPhase::LegendSelect))
+        );
+
+        for phase in enum_iterator::all::<MatchPhase>() {
+            assert!(
+                phase == MatchPhase::Lobby || phase.successors().contains(&MatchPhase::Lobby),
+                "{} can't go back to the lobby",
+                phase
+            );
+        }
+    }
+
+    #[test]
+    fn the_lobby_marker_is_read_in_every_phase() {
+        let layout = crate::layout::from_toml(include_str!("../layouts/apex.toml")).unwrap();
+        let regions = layout.regions();
+
+        for phase in enum_iterator::all::<MatchPhase>() {
+            assert!(
+                phase
+                    .regions(&regions)
+                    .get_value("lobby_marker".to_string())
+                    .is_some(),
+                "lobby_marker isn't read in {}",
+                phase
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn phase_changes_forget_earlier_reads() {
+        let state = new(3, 0.6, 1);
+        smoothed(&state, "12", 1.).await;
+        assert_eq!(smoothed(&state, "12", 1.).await, Some("12".to_string()));
+
+        assert!(state
+            .observe_phase(Some(MatchPhase::InMatch))
+            .await
+            .is_some());
+        assert_eq!(state.phase().await, Some(MatchPhase::InMatch));
+        assert_eq!(smoothed(&state, "12", 1.).await, None);
     }
 }
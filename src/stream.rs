@@ -1,141 +1,756 @@
 extern crate ffmpeg_next as ffmpeg;
 
+use crate::detect::{FrameKind, GameDetector};
+use crate::error::{Result, StreamCvError};
+use crate::games::SupportedGames;
 use crate::img::frame::Frame;
-use crate::{roi, Config};
-use chrono::Utc;
-use ffmpeg::format::{input, Pixel};
+use crate::roi::{self, RegionOfInterestList, StreamResolution};
+use crate::sink::GapRecord;
+use crate::Config;
+use chrono::{DateTime, Utc};
+use ffmpeg::format::{input_with_dictionary, Pixel};
 use ffmpeg::frame::Video;
 use ffmpeg::software::scaling::{context::Context as FFContext, flag::Flags};
-use ffmpeg::sys::{av_log_set_level, AV_LOG_QUIET};
+use ffmpeg::sys::{
+    av_frame_apply_cropping, av_log_set_level, AV_FRAME_CROP_UNALIGNED, AV_LOG_QUIET,
+    AV_NOPTS_VALUE, AV_TIME_BASE,
+};
+use ffmpeg::Rational;
+use ffmpeg::{Dictionary, Discard, Packet};
 use flume::Sender;
-use opencv::core::{Mat, UMat};
+use opencv::core::{Mat, Point, Rect, UMat, CV_8UC3};
 use opencv::prelude::MatTraitConst;
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Consecutive failed reads after which the input is treated as broken.
+const MAX_READ_ERRORS: u32 = 100;
+
+/// How a connection to the input came to an end.
+enum DecodeOutcome {
+    Eof,
+    Stalled,
+    Error(ffmpeg::Error),
+    /// The end time was reached, so nothing more should be decoded.
+    Finished,
+}
+
+struct Outage {
+    started_at: DateTime<Utc>,
+    reason: String,
+    attempts: u32,
+    last_timestamp: Option<f64>,
+}
+
+fn is_network_url(url: &str) -> bool {
+    url.contains("://") && !url.starts_with("file:")
+}
+
+/// The part of a `width` by `height` frame that has to be decoded for
+/// `regions`, grown to even coordinates so subsampled chroma planes are
+/// cropped along with the luma.
+fn decode_area(regions: &RegionOfInterestList, width: i32, height: i32) -> Option<Rect> {
+    let rect = regions.bounding_rect()?;
+
+    let left = (rect.x.max(0) / 2) * 2;
+    let top = (rect.y.max(0) / 2) * 2;
+    let right = ((rect.x + rect.width + 1) / 2 * 2).min(width);
+    let bottom = ((rect.y + rect.height + 1) / 2 * 2).min(height);
+
+    Some(Rect::new(left, top, right - left, bottom - top))
+}
+
+/// Crops `frame` to `area` by moving its data pointers, without copying.
+fn crop_decoded(frame: &mut Video, area: Rect) -> Result<()> {
+    let (width, height) = (frame.width() as i32, frame.height() as i32);
+    if area.x + area.width > width || area.y + area.height > height {
+        return Err(StreamCvError::Decode(ffmpeg::Error::InvalidData));
+    }
+
+    unsafe {
+        let frame = frame.as_mut_ptr();
+        (*frame).crop_left = area.x as usize;
+        (*frame).crop_top = area.y as usize;
+        (*frame).crop_right = (width - area.x - area.width) as usize;
+        (*frame).crop_bottom = (height - area.y - area.height) as usize;
+
+        match av_frame_apply_cropping(frame, AV_FRAME_CROP_UNALIGNED as i32) {
+            0 => Ok(()),
+            code => Err(StreamCvError::Decode(ffmpeg::Error::from(code))),
+        }
+    }
+}
 
 pub struct VideoStream {
     _width: i32,
     _height: i32,
-    _framerate: i32,
+    avg_frame_rate: f64,
     pub decoding: bool,
     pub frame_index: i64,
     pub frame_rate: i32,
+    frames_sent: i64,
+    time_base: Rational,
+    start_time: i64,
+    next_sample_time: Option<f64>,
+    frame_index_from_time: bool,
+    reached_end: bool,
+    seek_to: Option<f64>,
+    discard_before: Option<f64>,
+    live: bool,
+    received_packets: bool,
+    outage: Option<Outage>,
+    last_timestamp: Option<f64>,
+    resume_at: Option<f64>,
+    timestamp_offset: f64,
+    /// Part of the frame that's converted and sent on, the whole frame if
+    /// `None`.
+    decode_area: Option<Rect>,
+    /// The game whose regions the decode area covers.
+    area_game: Option<SupportedGames>,
+    pixel_format: Pixel,
     scaler: Option<FFContext>,
     sender: Sender<Frame>,
+    gap_sender: Sender<GapRecord>,
     config: Config,
+    detector: Arc<GameDetector>,
+    /// Each game's regions, resolved to the stream's resolution.
+    hud_regions: HashMap<SupportedGames, RegionOfInterestList>,
+    probe_regions: RegionOfInterestList,
+    next_probe: Option<f64>,
 }
 
 impl VideoStream {
-    pub fn new(config: Config, sender: Sender<Frame>) -> Self {
+    pub fn new(
+        config: Config,
+        sender: Sender<Frame>,
+        gap_sender: Sender<GapRecord>,
+        detector: Arc<GameDetector>,
+    ) -> Self {
         VideoStream {
             _width: 0,
             _height: 0,
-            _framerate: 0,
+            avg_frame_rate: 0.,
             decoding: false,
             scaler: None,
             frame_index: 0,
+            frames_sent: 0,
+            time_base: Rational::new(0, 1),
+            start_time: 0,
+            next_sample_time: None,
+            frame_index_from_time: false,
+            reached_end: false,
+            seek_to: config.start,
+            discard_before: config.start,
+            live: false,
+            received_packets: false,
+            outage: None,
+            last_timestamp: None,
+            resume_at: None,
+            timestamp_offset: 0.,
+            decode_area: None,
+            area_game: None,
+            pixel_format: Pixel::None,
             sender,
+            gap_sender,
             config,
             frame_rate: 0,
+            hud_regions: detector.hud_regions(),
+            probe_regions: detector.probe_regions(),
+            next_probe: None,
+            detector,
         }
     }
-    pub async fn decode(&mut self) {
-        ffmpeg::init().unwrap();
+    /// Number of frames sent to the pipeline so far.
+    pub fn frames_sent(&self) -> i64 {
+        self.frames_sent
+    }
+
+    /// Decodes the stream, reconnecting to network sources when they drop out.
+    pub async fn decode(&mut self) -> Result<()> {
+        ffmpeg::init().map_err(StreamCvError::Decode)?;
 
         unsafe { av_log_set_level(AV_LOG_QUIET) }
 
-        info!("****setting up decoder*****");
-        if let Ok(mut ictx) = input(&self.config.url) {
-            info!("*****building input*****");
-            let streams = ictx.streams();
-
-            if let Some(input) = streams.best(ffmpeg::media::Type::Video) {
-                let video_stream_index = input.index();
-                if let Some(comment) = input.metadata().get("comment") {
-                    info!("Stream spec: {}", comment);
+        let is_network = is_network_url(&self.config.url);
+        let mut attempts = 0;
+
+        loop {
+            let outcome = self.decode_input().await?;
+
+            if self.received_packets {
+                attempts = 0;
+            }
+
+            let (reason, err) = match outcome {
+                DecodeOutcome::Finished => break,
+                DecodeOutcome::Eof if !(is_network && self.live) => {
+                    info!("end of stream");
+                    break;
                 }
+                DecodeOutcome::Eof => ("stream ended".to_string(), ffmpeg::Error::Eof),
+                DecodeOutcome::Stalled => (
+                    "stream stalled".to_string(),
+                    ffmpeg::Error::Other {
+                        errno: ffmpeg::error::ETIMEDOUT,
+                    },
+                ),
+                DecodeOutcome::Error(err) => (format!("unable to decode stream: {}", err), err),
+            };
 
-                let mut context_decoder =
-                    ffmpeg::codec::context::Context::from_parameters(input.parameters()).unwrap();
+            if !is_network || attempts >= self.config.reconnect_attempts {
+                error!("giving up on the stream\t{}", reason);
+                if let Some(outage) = self.outage.take() {
+                    self.report_gap(outage, None).await;
+                }
+                return Err(StreamCvError::Decode(err));
+            }
 
-                context_decoder.set_threading(ffmpeg::threading::Config {
-                    count: self.config.num_libav_threads as usize,
-                    kind: ffmpeg::threading::Type::Frame,
-                    safe: true,
+            if self.outage.is_none() {
+                self.outage = Some(Outage {
+                    started_at: Utc::now(),
+                    reason: reason.clone(),
+                    attempts: 0,
+                    last_timestamp: self.last_timestamp,
                 });
+            }
+
+            attempts += 1;
+            if let Some(outage) = self.outage.as_mut() {
+                outage.attempts = attempts;
+            }
+
+            let backoff = Duration::from_secs(1 << attempts.min(6)).min(MAX_BACKOFF);
+            warn!(
+                "{}, reconnecting in {}s (attempt {} of {})",
+                reason,
+                backoff.as_secs(),
+                attempts,
+                self.config.reconnect_attempts
+            );
+            sleep(backoff).await;
+        }
+
+        Ok(())
+    }
+
+    async fn decode_input(&mut self) -> Result<DecodeOutcome> {
+        self.received_packets = false;
+
+        info!("****setting up decoder*****");
+        let mut options = Dictionary::new();
+        if is_network_url(&self.config.url) {
+            // makes blocking reads give up so stalls can be told apart from slow packets
+            let timeout_us = self.config.stall_timeout_ms * 1000;
+            options.set("rw_timeout", &timeout_us.to_string());
+        }
 
-                debug!("libav threading\t{:?}", context_decoder.threading());
-
-                let mut decoder = context_decoder.decoder().video().unwrap();
-
-                self.scaler = Some(
-                    FFContext::get(
-                        decoder.format(),
-                        decoder.width(),
-                        decoder.height(),
-                        Pixel::BGR24,
-                        decoder.width(),
-                        decoder.height(),
-                        Flags::LANCZOS,
-                    )
-                    .unwrap(),
-                );
-
-                info!("decoding stream");
-                self.decoding = true;
-
-                for (stream, packet) in ictx.packets() {
-                    if stream.index() == video_stream_index {
-                        match decoder.send_packet(&packet) {
-                            Ok(_) => self.receive_and_process_decoded_frames(&mut decoder).await,
-                            Err(error) => error!("{}", error.to_string()),
-                        }
+        let mut ictx = match input_with_dictionary(&self.config.url, options) {
+            Ok(ictx) => ictx,
+            Err(err) => {
+                error!("unable to open stream\t{}", err);
+                return Ok(DecodeOutcome::Error(err));
+            }
+        };
+
+        info!("*****building input*****");
+        // live streams don't know how long they are
+        self.live = ictx.duration() <= 0;
+
+        let streams = ictx.streams();
+        let input = match streams.best(ffmpeg::media::Type::Video) {
+            Some(input) => input,
+            None => {
+                error!("no video stream available");
+                return Err(StreamCvError::Decode(ffmpeg::Error::StreamNotFound));
+            }
+        };
+
+        let video_stream_index = input.index();
+        self.time_base = input.time_base();
+        self.start_time = match input.start_time() {
+            AV_NOPTS_VALUE => 0,
+            start_time => start_time,
+        };
+        self.avg_frame_rate = match f64::from(input.avg_frame_rate()) {
+            fps if fps > 0. => fps,
+            _ => {
+                warn!("stream has no average frame rate, assuming 30 fps");
+                30.
+            }
+        };
+        self.frame_rate = self.avg_frame_rate.round() as i32;
+        if let Some(comment) = input.metadata().get("comment") {
+            info!("Stream spec: {}", comment);
+        }
+
+        let mut context_decoder =
+            ffmpeg::codec::context::Context::from_parameters(input.parameters())
+                .map_err(StreamCvError::Decode)?;
+
+        context_decoder.set_threading(ffmpeg::threading::Config {
+            count: self.config.num_libav_threads as usize,
+            kind: ffmpeg::threading::Type::Frame,
+            safe: true,
+        });
+
+        debug!("libav threading\t{:?}", context_decoder.threading());
+
+        let mut decoder = context_decoder
+            .decoder()
+            .video()
+            .map_err(StreamCvError::Decode)?;
+
+        let width = decoder.width() as i32;
+        let height = decoder.height() as i32;
+        if width <= 0 || height <= 0 {
+            error!("stream reported an invalid resolution {}x{}", width, height);
+            return Err(StreamCvError::Decode(ffmpeg::Error::InvalidData));
+        }
+
+        let source = StreamResolution::new(width, height);
+        if (source.aspect_ratio() - StreamResolution::HD1080P.aspect_ratio()).abs() > 0.01 {
+            warn!(
+                "stream is {}x{}, regions will be mapped onto a letterboxed 16:9 picture",
+                width, height
+            );
+        }
+
+        info!("scaling regions to {}x{}", width, height);
+        self._width = width;
+        self._height = height;
+        self.hud_regions = self
+            .detector
+            .hud_regions()
+            .into_iter()
+            .map(|(game, regions)| (game, regions.resolve(&source)))
+            .collect();
+        self.probe_regions = self.detector.probe_regions().resolve(&source);
+
+        self.pixel_format = decoder.format();
+        self.set_decode_area(self.detector.active())?;
+
+        if let Some(every) = self.config.process_frame_rate {
+            info!(
+                "sampling every {} frame(s) of a {:.2} fps stream",
+                every, self.avg_frame_rate
+            );
+        } else if self.config.sample_fps > 0. {
+            info!(
+                "sampling {} fps from a {:.2} fps stream",
+                self.config.sample_fps, self.avg_frame_rate
+            );
+        }
+        if self.config.keyframes_only {
+            // the decoder still sees every packet so references stay intact,
+            // but only returns keyframes
+            decoder.skip_frame(Discard::NonKey);
+            info!("decoding keyframes only");
+        }
+
+        if let Some(outage) = self.outage.take() {
+            info!("reconnected to the stream");
+            self.resume_after(&outage);
+            self.report_gap(outage, Some(Utc::now())).await;
+        }
+
+        if let Some(position) = self.seek_to.take() {
+            // seek positions are absolute, timestamps are relative to the stream start
+            let stream_start = self.start_time as f64 * f64::from(self.time_base);
+            let target = ((position + stream_start) * AV_TIME_BASE as f64) as i64;
+
+            match ictx.seek(target, ..target) {
+                Ok(_) => {
+                    info!("seeked to the keyframe before {:.3}s", position);
+                    self.frame_index_from_time = true;
+                }
+                Err(err) => {
+                    error!("unable to seek to {:.3}s\t{}", position, err);
+                    return Err(StreamCvError::Decode(err));
+                }
+            }
+        }
+
+        info!("decoding stream");
+        self.decoding = true;
+
+        let stall_timeout = Duration::from_millis(self.config.stall_timeout_ms);
+        let mut last_packet = Instant::now();
+        let mut read_errors = 0;
+
+        let outcome = loop {
+            if self.reached_end {
+                break DecodeOutcome::Finished;
+            }
+
+            let mut packet = Packet::empty();
+            match packet.read(&mut ictx) {
+                Ok(_) => {
+                    last_packet = Instant::now();
+                    read_errors = 0;
+                    self.received_packets = true;
+                }
+                Err(ffmpeg::Error::Eof) => break DecodeOutcome::Eof,
+                Err(_) if last_packet.elapsed() >= stall_timeout => break DecodeOutcome::Stalled,
+                Err(err) => {
+                    read_errors += 1;
+                    if read_errors >= MAX_READ_ERRORS {
+                        break DecodeOutcome::Error(err);
                     }
+                    continue;
                 }
+            }
 
-                decoder.send_eof().unwrap();
-                self.receive_and_process_decoded_frames(&mut decoder).await;
+            if packet.stream() == video_stream_index {
+                match decoder.send_packet(&packet) {
+                    Ok(_) => {
+                        self.receive_and_process_decoded_frames(&mut decoder)
+                            .await?
+                    }
+                    Err(error) => error!("{}", error.to_string()),
+                }
+            }
+        };
 
-                self.decoding = false;
-            } else {
-                error!("no streams available");
+        if let DecodeOutcome::Eof = outcome {
+            match decoder.send_eof() {
+                Ok(_) => {
+                    self.receive_and_process_decoded_frames(&mut decoder)
+                        .await?
+                }
+                Err(err) => error!("unable to flush the decoder\t{}", err),
             }
         }
+
+        self.decoding = false;
+
+        if self.reached_end {
+            Ok(DecodeOutcome::Finished)
+        } else {
+            Ok(outcome)
+        }
+    }
+
+    /// Picks up where the stream left off before `outage`.
+    ///
+    /// Files are seeked back to the last frame. Live streams can't be, so
+    /// their timestamps are shifted to continue from the last frame plus the
+    /// time spent reconnecting.
+    fn resume_after(&mut self, outage: &Outage) {
+        let last_timestamp = match outage.last_timestamp {
+            Some(last_timestamp) => last_timestamp,
+            None => return,
+        };
+
+        if self.live {
+            let downtime = (Utc::now() - outage.started_at).num_milliseconds() as f64 / 1000.;
+            self.resume_at = Some(last_timestamp + downtime);
+        } else {
+            self.seek_to = Some(last_timestamp);
+            self.discard_before = Some(last_timestamp + 0.5 / self.avg_frame_rate);
+        }
+
+        self.frame_index_from_time = true;
+    }
+
+    async fn report_gap(&self, outage: Outage, resumed_at: Option<DateTime<Utc>>) {
+        let gap = GapRecord {
+            seq: self.frames_sent,
+            reason: outage.reason,
+            last_timestamp: outage.last_timestamp,
+            started_at: outage.started_at,
+            resumed_at,
+            reconnect_attempts: outage.attempts,
+        };
+
+        if let Err(err) = self.gap_sender.send_async(gap).await {
+            error!("unable to report stream gap\t{}", err);
+        }
+    }
+
+    fn pts_to_seconds(&self, pts: i64) -> f64 {
+        (pts - self.start_time) as f64 * f64::from(self.time_base)
+    }
+
+    /// Decides whether the frame shown at `timestamp` is analyzed, keeping
+    /// sampled frames `1 / sample_fps` seconds apart regardless of the
+    /// source's frame rate.
+    fn should_sample(&mut self, timestamp: f64) -> bool {
+        let interval = match self.config.process_frame_rate {
+            Some(every) => every as f64 / self.avg_frame_rate,
+            None if self.config.sample_fps > 0. => 1. / self.config.sample_fps,
+            None => return true,
+        };
+
+        sample_due(&mut self.next_sample_time, timestamp, interval)
+    }
+
+    /// The regions to read in the frame shown at `timestamp`, `None` while
+    /// no known game is on screen and no probe is due.
+    fn frame_regions(&mut self, timestamp: f64) -> Option<(FrameKind, RegionOfInterestList)> {
+        if self.detector.is_detecting() {
+            let interval = self.detector.interval();
+            // timestamps go back after seeking, which starts probing afresh
+            let due = self
+                .next_probe
+                .is_none_or(|next| timestamp >= next - 0.001 || timestamp < next - interval);
+
+            if due {
+                self.next_probe = Some(timestamp + interval);
+                return Some((FrameKind::Probe, self.probe_regions.clone()));
+            }
+        }
+
+        let game = self.detector.active()?;
+        let regions = self.hud_regions.get(&game)?;
+
+        Some((FrameKind::Hud(game), regions.clone()))
+    }
+
+    /// Sets the decode area to cover `game`'s regions, and the probe regions
+    /// while detecting, and builds the scaler for its size. Only the active
+    /// game's regions are covered, as the union of every game's would be
+    /// most of the frame.
+    fn set_decode_area(&mut self, game: Option<SupportedGames>) -> Result<()> {
+        let (width, height) = (self._width, self._height);
+
+        let mut regions = if self.detector.is_detecting() {
+            self.probe_regions.clone()
+        } else {
+            roi::new_region_list()
+        };
+        if let Some(hud) = game.and_then(|game| self.hud_regions.get(&game)) {
+            for (_, region) in hud.iter() {
+                regions.add_region(region);
+            }
+        }
+
+        self.area_game = game;
+        self.decode_area = if self.config.full_frame {
+            None
+        } else {
+            decode_area(&regions, width, height)
+        };
+        let area = self
+            .decode_area
+            .unwrap_or_else(|| Rect::new(0, 0, width, height));
+        if self.decode_area.is_some() {
+            info!(
+                "decoding the {}x{} area at {},{} the regions cover",
+                area.width, area.height, area.x, area.y
+            );
+        }
+
+        // frames are only converted to BGR, never resized, so the fastest
+        // filter does as well as any
+        self.scaler = Some(
+            FFContext::get(
+                self.pixel_format,
+                area.width as u32,
+                area.height as u32,
+                Pixel::BGR24,
+                area.width as u32,
+                area.height as u32,
+                Flags::FAST_BILINEAR,
+            )
+            .map_err(StreamCvError::Scale)?,
+        );
+
+        Ok(())
+    }
+
+    /// Crops a decoded frame to the decode area, converts it to BGR and
+    /// copies it into OpenCV.
+    fn convert(&mut self, decoded: &mut Video) -> Result<(Mat, UMat)> {
+        if let Some(area) = self.decode_area {
+            crop_decoded(decoded, area)?;
+        }
+
+        let mut bgr_frame = Video::empty();
+
+        if let Some(scaler) = self.scaler.as_mut() {
+            scaler
+                .run(decoded, &mut bgr_frame)
+                .map_err(StreamCvError::Scale)?;
+        }
+
+        // rows can be padded past the width, so the frame's stride is used
+        let stride = bgr_frame.stride(0);
+        let borrowed = unsafe {
+            Mat::new_rows_cols_with_data(
+                bgr_frame.height() as i32,
+                bgr_frame.width() as i32,
+                CV_8UC3,
+                bgr_frame.data_mut(0).as_mut_ptr() as *mut c_void,
+                stride,
+            )?
+        };
+
+        // the ffmpeg frame is freed on return, so the mat gets its own copy
+        let mut mat = Mat::default();
+        borrowed.copy_to(&mut mat)?;
+
+        let mut bgr_umat = UMat::new(opencv::core::UMatUsageFlags::USAGE_DEFAULT);
+        mat.copy_to(&mut bgr_umat)?;
+
+        Ok((mat, bgr_umat))
     }
 
-    async fn receive_and_process_decoded_frames(&mut self, decoder: &mut ffmpeg::decoder::Video) {
+    async fn receive_and_process_decoded_frames(
+        &mut self,
+        decoder: &mut ffmpeg::decoder::Video,
+    ) -> Result<()> {
         let mut decoded = Video::empty();
 
         while decoder.receive_frame(&mut decoded).is_ok() {
-            if self.frame_index.rem_euclid(self.config.process_frame_rate) != 0 {
+            let pts = decoded.timestamp().or_else(|| decoded.pts());
+            let mut timestamp = match pts {
+                Some(pts) => self.pts_to_seconds(pts),
+                None => self.frame_index as f64 / self.avg_frame_rate,
+            };
+
+            if let Some(resume_at) = self.resume_at.take() {
+                self.timestamp_offset = resume_at - timestamp;
+            }
+            timestamp += self.timestamp_offset;
+
+            if let Some(end) = self.config.end {
+                if timestamp > end {
+                    info!("reached the end time {:.3}s", end);
+                    self.reached_end = true;
+                    return Ok(());
+                }
+            }
+
+            if let Some(start) = self.discard_before {
+                // decoding starts at the keyframe before the position seeked to
+                if timestamp < start - 0.001 {
+                    continue;
+                }
+            }
+
+            // after a seek, or when the decoder only returns keyframes, the
+            // decoded frame count no longer matches the position in the video
+            if self.config.keyframes_only || self.frame_index_from_time {
+                self.frame_index = (timestamp * self.avg_frame_rate).round() as i64;
+                self.frame_index_from_time = false;
+            }
+
+            if !self.should_sample(timestamp) {
                 self.frame_index += 1;
                 continue;
             }
-            let mut rgb_frame = Video::empty();
 
-            self.scaler
-                .as_mut()
-                .unwrap()
-                .run(&decoded, &mut rgb_frame)
-                .unwrap();
+            let (kind, results) = match self.frame_regions(timestamp) {
+                Some(regions) => regions,
+                None => {
+                    self.frame_index += 1;
+                    continue;
+                }
+            };
 
-            let mut bgr_umat = UMat::new(opencv::core::UMatUsageFlags::USAGE_DEFAULT);
+            if let FrameKind::Hud(game) = kind {
+                if self.area_game != Some(game) {
+                    self.set_decode_area(Some(game))?;
+                }
+            }
 
-            let mut mat = Mat::from_slice(rgb_frame.data_mut(0)).unwrap();
-            mat = mat.reshape(3, rgb_frame.height() as i32).unwrap();
-            mat.copy_to(&mut bgr_umat).unwrap();
+            let (mat, bgr_umat) = match self.convert(&mut decoded) {
+                Ok(converted) => converted,
+                Err(err) => {
+                    error!("frame {}\tskipping frame\t{}", self.frame_index, err);
+                    self.frame_index += 1;
+                    continue;
+                }
+            };
 
             let new_frame = crate::img::frame::Frame {
                 mat,
+                offset: self
+                    .decode_area
+                    .map_or_else(Point::default, |area| Point::new(area.x, area.y)),
+                kind,
                 num: self.frame_index,
+                seq: self.frames_sent,
+                pts,
+                timestamp: Some(timestamp),
                 processed_mat: bgr_umat,
                 text: "".to_string(),
                 start_date: Utc::now(),
                 end_date: None,
-                results: roi::new_region_list(),
+                results,
             };
 
-            self.sender.send_async(new_frame).await.unwrap();
+            self.sender
+                .send_async(new_frame)
+                .await
+                .map_err(|_| StreamCvError::PipelineClosed)?;
+            self.frames_sent += 1;
+            self.last_timestamp = Some(timestamp);
 
             self.frame_index += 1;
         }
+
+        Ok(())
+    }
+}
+
+/// Whether the frame shown at `timestamp` is due when sampling a frame every
+/// `interval` seconds, moving `next` on to when the one after it is.
+fn sample_due(next: &mut Option<f64>, timestamp: f64, interval: f64) -> bool {
+    match *next {
+        // timestamps aren't exact, so allow for a millisecond of drift
+        Some(due) if timestamp < due - 0.001 => false,
+        due => {
+            let due = due.unwrap_or(timestamp) + interval;
+            *next = Some(if due <= timestamp {
+                timestamp + interval
+            } else {
+                due
+            });
+
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sampled(fps: f64, frames: usize, interval: f64) -> Vec<usize> {
+        let mut next = None;
+
+        (0..frames)
+            .filter(|i| sample_due(&mut next, *i as f64 / fps, interval))
+            .collect()
+    }
+
+    #[test]
+    fn samples_by_time_not_frame_count() {
+        assert_eq!(sampled(60., 12, 1. / 15.), vec![0, 4, 8]);
+        assert_eq!(sampled(30., 6, 1. / 15.), vec![0, 2, 4]);
+        assert_eq!(sampled(29.97, 6, 1. / 15.), vec![0, 2, 4]);
+    }
+
+    #[test]
+    fn slow_sources_are_sampled_every_frame() {
+        assert_eq!(sampled(10., 4, 1. / 15.), vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn sampling_restarts_after_a_jump() {
+        let mut next = None;
+        let interval = 0.5;
+
+        assert!(sample_due(&mut next, 0., interval));
+        assert!(!sample_due(&mut next, 0.25, interval));
+        assert!(sample_due(&mut next, 10., interval));
+        assert!(!sample_due(&mut next, 10.25, interval));
+        assert!(sample_due(&mut next, 10.5, interval));
     }
 }